@@ -0,0 +1,443 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use md5::Md5;
+use reqwest::Method;
+use reqwest::header::{HeaderMap, WWW_AUTHENTICATE};
+use sha2::{Digest as _, Sha256, Sha512_256};
+
+use crate::body::RequestBody;
+use crate::cli::Cli;
+use crate::sigv4::{AwsCredentials, SigV4};
+
+// Credentials sent with each request. Basic and Bearer go out with the first
+// request; Digest answers the server's 401 challenge; SigV4 signs each request.
+#[derive(Clone, PartialEq)]
+pub enum Auth {
+    Basic { user: String, password: String },
+    Bearer(String),
+    Digest { user: String, password: String },
+    AwsSigV4(SigV4),
+}
+
+// Never print the secrets, even in debug logs
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Auth::Basic { user, .. } => write!(f, "Basic({}:***)", user),
+            Auth::Bearer(_) => write!(f, "Bearer(***)"),
+            Auth::Digest { user, .. } => write!(f, "Digest({}:***)", user),
+            Auth::AwsSigV4(signer) => write!(f, "AwsSigV4({:?})", signer),
+        }
+    }
+}
+
+impl Auth {
+    // Build the credentials from -u, --bearer, --bearer-file and --aws-sigv4,
+    // prompting for the password when -u has none
+    pub fn from_cli(cli: &Cli) -> Result<Option<Auth>> {
+        Self::from_cli_with_prompt(cli, |user| {
+            Ok(rpassword::prompt_password(format!("Enter host password for user '{}': ", user))?)
+        })
+    }
+
+    fn from_cli_with_prompt(cli: &Cli, prompt: impl FnOnce(&str) -> Result<String>) -> Result<Option<Auth>> {
+        if let Some(scope) = &cli.aws_sigv4 {
+            let credentials = AwsCredentials::load()?;
+            return Ok(Some(Auth::AwsSigV4(SigV4 { scope: scope.clone(), credentials })));
+        }
+
+        if let Some(token) = &cli.bearer {
+            return Ok(Some(Auth::Bearer(token.clone())));
+        }
+
+        if let Some(path) = &cli.bearer_file {
+            let token = std::fs::read_to_string(path)?.trim().to_string();
+            if token.is_empty() {
+                bail!("Bearer token file {} is empty", path);
+            }
+            return Ok(Some(Auth::Bearer(token)));
+        }
+
+        let Some(arg) = &cli.user else { return Ok(None) };
+        // Like curl, "user:" means an empty password and "user" asks for one
+        let (user, password) = match arg.split_once(':') {
+            Some((user, password)) => (user.to_string(), password.to_string()),
+            None => (arg.clone(), prompt(arg)?),
+        };
+
+        if cli.digest {
+            Ok(Some(Auth::Digest { user, password }))
+        } else {
+            Ok(Some(Auth::Basic { user, password }))
+        }
+    }
+
+    // The Authorization header to send up front, or None when it has to wait
+    // for a challenge
+    pub fn preemptive_header(&self) -> Option<String> {
+        match self {
+            Auth::Basic { user, password } => Some(format!("Basic {}", STANDARD.encode(format!("{}:{}", user, password)))),
+            Auth::Bearer(token) => Some(format!("Bearer {}", token)),
+            Auth::Digest { .. } | Auth::AwsSigV4(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DigestAlgorithm {
+    Md5,
+    Sha256,
+    Sha512_256,
+}
+
+impl DigestAlgorithm {
+    fn hash(self, data: &str) -> String {
+        match self {
+            DigestAlgorithm::Md5 => format!("{:x}", Md5::digest(data)),
+            DigestAlgorithm::Sha256 => format!("{:x}", Sha256::digest(data)),
+            DigestAlgorithm::Sha512_256 => format!("{:x}", Sha512_256::digest(data)),
+        }
+    }
+
+    fn hash_bytes(self, data: &[u8]) -> String {
+        match self {
+            DigestAlgorithm::Md5 => format!("{:x}", Md5::digest(data)),
+            DigestAlgorithm::Sha256 => format!("{:x}", Sha256::digest(data)),
+            DigestAlgorithm::Sha512_256 => format!("{:x}", Sha512_256::digest(data)),
+        }
+    }
+}
+
+// A Digest WWW-Authenticate challenge (RFC 7616)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    // The name as the server sent it, e.g. MD5 or SHA-256-sess
+    algorithm_name: Option<String>,
+    algorithm: DigestAlgorithm,
+    session: bool,
+    qop: Vec<String>,
+}
+
+impl DigestChallenge {
+    // The first Digest challenge among the response's WWW-Authenticate
+    // headers whose algorithm we support
+    pub fn from_headers(headers: &HeaderMap) -> Option<DigestChallenge> {
+        headers.get_all(WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(DigestChallenge::parse)
+    }
+
+    pub fn parse(header: &str) -> Option<DigestChallenge> {
+        let header = header.trim();
+        let (scheme, rest) = header.split_once(char::is_whitespace)?;
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return None;
+        }
+
+        let params = parse_auth_params(rest);
+        let param = |name: &str| params.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone());
+
+        let algorithm_name = param("algorithm");
+        let name = algorithm_name.clone().unwrap_or_else(|| "MD5".to_string()).to_ascii_uppercase();
+        let (base, session) = match name.strip_suffix("-SESS") {
+            Some(base) => (base.to_string(), true),
+            None => (name, false),
+        };
+        let algorithm = match base.as_str() {
+            "MD5" => DigestAlgorithm::Md5,
+            "SHA-256" => DigestAlgorithm::Sha256,
+            "SHA-512-256" => DigestAlgorithm::Sha512_256,
+            _ => return None,
+        };
+
+        Some(DigestChallenge {
+            realm: param("realm").unwrap_or_default(),
+            nonce: param("nonce")?,
+            opaque: param("opaque"),
+            algorithm_name,
+            algorithm,
+            session,
+            qop: param("qop")
+                .map(|qop| qop.split(',').map(|q| q.trim().to_ascii_lowercase()).collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    // The Authorization header answering this challenge
+    pub fn respond(&self, user: &str, password: &str, method: &Method, url: &str, body: Option<&RequestBody>) -> Result<String> {
+        self.respond_with_cnonce(user, password, method, url, body, &new_cnonce())
+    }
+
+    fn respond_with_cnonce(
+        &self,
+        user: &str,
+        password: &str,
+        method: &Method,
+        url: &str,
+        body: Option<&RequestBody>,
+        cnonce: &str,
+    ) -> Result<String> {
+        let uri = request_target(url)?;
+        let alg = self.algorithm;
+        let nc = "00000001";
+
+        // auth-int covers the body too, which is only possible when it's in memory
+        let qop = if self.qop.is_empty() {
+            None
+        } else if self.qop.iter().any(|q| q == "auth") {
+            Some("auth")
+        } else if self.qop.iter().any(|q| q == "auth-int") {
+            Some("auth-int")
+        } else {
+            bail!("Unsupported Digest qop {}", self.qop.join(","));
+        };
+
+        let mut ha1 = alg.hash(&format!("{}:{}:{}", user, self.realm, password));
+        if self.session {
+            ha1 = alg.hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+
+        let ha2 = if qop == Some("auth-int") {
+            let body_hash = match body {
+                None => alg.hash_bytes(b""),
+                Some(RequestBody::Bytes(data)) => alg.hash_bytes(data),
+                Some(RequestBody::File { .. }) => bail!("Digest auth-int can't be used with a streamed body"),
+            };
+            alg.hash(&format!("{}:{}:{}", method, uri, body_hash))
+        } else {
+            alg.hash(&format!("{}:{}", method, uri))
+        };
+
+        let response = match qop {
+            Some(qop) => alg.hash(&format!("{}:{}:{}:{}:{}:{}", ha1, self.nonce, nc, cnonce, qop, ha2)),
+            None => alg.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2)),
+        };
+
+        let mut header = format!(
+            r#"Digest username="{}", realm="{}", nonce="{}", uri="{}""#,
+            quote(user), quote(&self.realm), quote(&self.nonce), quote(&uri)
+        );
+        if let Some(algorithm) = &self.algorithm_name {
+            header.push_str(&format!(", algorithm={}", algorithm));
+        }
+        header.push_str(&format!(r#", response="{}""#, response));
+        if let Some(opaque) = &self.opaque {
+            header.push_str(&format!(r#", opaque="{}""#, quote(opaque)));
+        }
+        if let Some(qop) = qop {
+            header.push_str(&format!(r#", qop={}, nc={}, cnonce="{}""#, qop, nc, cnonce));
+        }
+
+        Ok(header)
+    }
+}
+
+// Split `key=value, key="quoted, value"` pairs
+fn parse_auth_params(s: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ',')).collect();
+        if key.trim().is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'=').is_some() {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.next_if_eq(&'"').is_some() {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next()),
+                        '"' => break,
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                value = std::iter::from_fn(|| chars.next_if(|c| *c != ',')).collect::<String>().trim().to_string();
+            }
+        }
+
+        params.push((key.trim().to_string(), value));
+    }
+
+    params
+}
+
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// The path and query the request line carries
+fn request_target(url: &str) -> Result<String> {
+    let url = url::Url::parse(url)?;
+    Ok(match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    })
+}
+
+// A client nonce that differs between calls. It only needs to be
+// unpredictable enough to vary, not secret.
+fn new_cnonce() -> String {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let seed = format!("{}:{}:{}", nanos, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:x}", Md5::digest(seed))[..16].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_cli_builds_each_kind() -> Result<()> {
+        let no_prompt = |_: &str| -> Result<String> { panic!("Should not prompt") };
+
+        let cli = Cli { user: Some("alice:secret".to_string()), ..Default::default() };
+        assert_eq!(
+            Auth::from_cli_with_prompt(&cli, no_prompt)?,
+            Some(Auth::Basic { user: "alice".to_string(), password: "secret".to_string() })
+        );
+
+        // An empty password is still a password
+        let cli = Cli { user: Some("alice:".to_string()), digest: true, ..Default::default() };
+        assert_eq!(
+            Auth::from_cli_with_prompt(&cli, no_prompt)?,
+            Some(Auth::Digest { user: "alice".to_string(), password: String::new() })
+        );
+
+        let cli = Cli { bearer: Some("tok".to_string()), ..Default::default() };
+        assert_eq!(Auth::from_cli_with_prompt(&cli, no_prompt)?, Some(Auth::Bearer("tok".to_string())));
+
+        assert_eq!(Auth::from_cli_with_prompt(&Cli::default(), no_prompt)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn from_cli_prompts_for_missing_password() -> Result<()> {
+        let cli = Cli { user: Some("alice".to_string()), ..Default::default() };
+
+        let auth = Auth::from_cli_with_prompt(&cli, |user| {
+            assert_eq!(user, "alice");
+            Ok("typed".to_string())
+        })?;
+
+        assert_eq!(auth, Some(Auth::Basic { user: "alice".to_string(), password: "typed".to_string() }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn from_cli_reads_bearer_file() -> Result<()> {
+        use std::io::Write;
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "  file-token  ")?;
+        let cli = Cli { bearer_file: Some(file.path().display().to_string()), ..Default::default() };
+
+        let auth = Auth::from_cli_with_prompt(&cli, |_| unreachable!())?;
+
+        assert_eq!(auth, Some(Auth::Bearer("file-token".to_string())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn preemptive_headers() {
+        let basic = Auth::Basic { user: "Aladdin".to_string(), password: "open sesame".to_string() };
+        assert_eq!(basic.preemptive_header().unwrap(), "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+        assert_eq!(Auth::Bearer("abc".to_string()).preemptive_header().unwrap(), "Bearer abc");
+        assert!(Auth::Digest { user: "u".to_string(), password: "p".to_string() }.preemptive_header().is_none());
+    }
+
+    #[test]
+    fn debug_hides_secrets() {
+        let auth = Auth::Basic { user: "alice".to_string(), password: "secret".to_string() };
+        assert!(!format!("{:?}", auth).contains("secret"));
+        assert!(!format!("{:?}", Auth::Bearer("secret".to_string())).contains("secret"));
+    }
+
+    #[test]
+    fn parse_challenge() {
+        let challenge = DigestChallenge::parse(
+            r#"Digest realm="api, \"v2\"", qop="auth,auth-int", algorithm=SHA-256-sess, nonce="abc", opaque="xyz""#
+        ).unwrap();
+
+        assert_eq!(challenge.realm, r#"api, "v2""#);
+        assert_eq!(challenge.nonce, "abc");
+        assert_eq!(challenge.opaque.as_deref(), Some("xyz"));
+        assert_eq!(challenge.algorithm, DigestAlgorithm::Sha256);
+        assert!(challenge.session);
+        assert_eq!(challenge.qop, vec!["auth", "auth-int"]);
+
+        assert!(DigestChallenge::parse(r#"Basic realm="api""#).is_none());
+        assert!(DigestChallenge::parse(r#"Digest realm="api", nonce="n", algorithm=SHA-1"#).is_none());
+    }
+
+    // RFC 2617 section 3.5
+    #[test]
+    fn respond_rfc2617_example() -> Result<()> {
+        let challenge = DigestChallenge::parse(
+            r#"Digest realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#
+        ).unwrap();
+
+        let header = challenge.respond_with_cnonce(
+            "Mufasa", "Circle Of Life", &Method::GET, "http://www.nowhere.org/dir/index.html", None, "0a4f113b",
+        )?;
+
+        assert_eq!(header, concat!(
+            r#"Digest username="Mufasa", realm="testrealm@host.com", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", "#,
+            r#"uri="/dir/index.html", response="6629fae49393a05397450978507c4ef1", "#,
+            r#"opaque="5ccc069c403ebaf9f0171e9517f40e41", qop=auth, nc=00000001, cnonce="0a4f113b""#,
+        ));
+
+        Ok(())
+    }
+
+    // RFC 7616 section 3.9.1
+    #[test]
+    fn respond_rfc7616_sha256_example() -> Result<()> {
+        let challenge = DigestChallenge::parse(
+            r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm=SHA-256, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#
+        ).unwrap();
+
+        let header = challenge.respond_with_cnonce(
+            "Mufasa", "Circle of Life", &Method::GET, "http://www.example.org/dir/index.html", None,
+            "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+        )?;
+
+        assert!(header.contains(r#"response="753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1""#));
+        assert!(header.contains("algorithm=SHA-256"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn respond_auth_int_needs_in_memory_body() {
+        let challenge = DigestChallenge::parse(r#"Digest realm="r", nonce="n", qop="auth-int""#).unwrap();
+        let file = RequestBody::File { path: "/tmp/body".into(), len: 1 };
+
+        assert!(challenge.respond("u", "p", &Method::POST, "http://h/x", Some(&RequestBody::from("x"))).is_ok());
+        assert!(challenge.respond("u", "p", &Method::POST, "http://h/x", Some(&file)).is_err());
+    }
+
+    #[test]
+    fn cnonces_differ() {
+        assert_ne!(new_cnonce(), new_cnonce());
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+use crate::body::RequestBody;
+use crate::checksum::DigestOptions;
+use crate::cli::{CliMethod, valid_url};
+use crate::http::{RequestSpec, default_header};
+
+// One line of a batch file (JSON Lines), e.g.
+//   {"url": "https://example.com/items", "method": "POST",
+//    "headers": {"X-Trace": "smoke"}, "json": {"name": "x"}, "expect_status": 201}
+// Only url is required. body takes a string (or @file), json any JSON value.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BatchEntry {
+    pub url: String,
+    pub method: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: Option<String>,
+    pub json: Option<serde_json::Value>,
+    pub expect_status: Option<u16>,
+}
+
+impl BatchEntry {
+    pub fn parse(line: &str) -> Result<BatchEntry> {
+        Ok(serde_json::from_str(line)?)
+    }
+
+    // Check an entry without reading any body files. Errors stop the run,
+    // warnings don't.
+    pub fn validate(&self) -> (Vec<String>, Vec<String>) {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+
+        if !valid_url(&self.url) {
+            errors.push(format!("Invalid URL {}: must start with http:// or https://", self.url));
+        }
+
+        let method = match self.method.as_deref().map(str::parse::<CliMethod>).transpose() {
+            Ok(method) => method.unwrap_or_default(),
+            Err(e) => {
+                errors.push(e);
+                CliMethod::default()
+            }
+        };
+
+        if self.body.is_some() && self.json.is_some() {
+            errors.push("Can't have both body and json".to_string());
+        }
+
+        if (self.body.is_some() || self.json.is_some()) && !method.allows_body() {
+            warnings.push(format!("Body not allowed for {}", method));
+        }
+
+        if let Some(body) = &self.body
+            && let Some(path) = body.strip_prefix('@') {
+            if path == "-" {
+                errors.push("Can't read a batch body from stdin".to_string());
+            } else if !Path::new(path).is_file() {
+                errors.push(format!("Body file {} not found", path));
+            }
+        }
+
+        if let Some(status) = self.expect_status
+            && !(100..600).contains(&status) {
+            errors.push(format!("Invalid expect_status {}", status));
+        }
+
+        (errors, warnings)
+    }
+
+    // Turn the entry into a request. The shared -H headers apply to every
+    // entry unless the entry sets the same header itself.
+    pub fn into_spec(self, shared_headers: &[(String, String)]) -> Result<RequestSpec> {
+        let method = match &self.method {
+            Some(method) => method.parse::<CliMethod>().map_err(anyhow::Error::msg)?,
+            None => CliMethod::default(),
+        };
+
+        let mut headers: Vec<(String, String)> = self.headers.into_iter().collect();
+        for (key, value) in shared_headers {
+            default_header(&mut headers, key, value);
+        }
+
+        let body = match (self.body, self.json) {
+            (Some(_), Some(_)) => bail!("Can't have both body and json"),
+            (Some(body), None) => Some(RequestBody::load(&body)?),
+            (None, Some(json)) => {
+                default_header(&mut headers, "Content-Type", "application/json");
+                default_header(&mut headers, "Accept", "application/json");
+                Some(RequestBody::from(json.to_string().as_str()))
+            }
+            (None, None) => None,
+        };
+
+        Ok(RequestSpec {
+            url: self.url,
+            method,
+            headers,
+            body,
+            auth: None,
+            expect_status: self.expect_status,
+            download: None,
+            digests: DigestOptions::default(),
+            invalid: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_minimal_entry() -> Result<()> {
+        let entry = BatchEntry::parse(r#"{"url": "https://example.com"}"#)?;
+
+        assert_eq!(entry, BatchEntry { url: "https://example.com".to_string(), ..Default::default() });
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_rejects_unknown_fields() {
+        assert!(BatchEntry::parse(r#"{"url": "https://example.com", "methd": "POST"}"#).is_err());
+        assert!(BatchEntry::parse(r#"{"method": "POST"}"#).is_err());
+    }
+
+    #[test]
+    fn validate_reports_problems() {
+        let entry = BatchEntry {
+            url: "ftp://example.com".to_string(),
+            method: Some("BAD VERB".to_string()),
+            body: Some("x".to_string()),
+            json: Some(serde_json::json!({})),
+            expect_status: Some(42),
+            ..Default::default()
+        };
+
+        let (errors, _) = entry.validate();
+
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].contains("Invalid URL ftp://example.com"));
+        assert!(errors[1].contains("invalid method `BAD VERB`"));
+        assert!(errors[2].contains("Can't have both body and json"));
+        assert!(errors[3].contains("Invalid expect_status 42"));
+    }
+
+    #[test]
+    fn validate_warns_about_body_on_get() {
+        let entry = BatchEntry {
+            url: "https://example.com".to_string(),
+            body: Some("x".to_string()),
+            ..Default::default()
+        };
+
+        let (errors, warnings) = entry.validate();
+
+        assert!(errors.is_empty());
+        assert_eq!(warnings, vec!["Body not allowed for GET".to_string()]);
+    }
+
+    #[test]
+    fn into_spec_json_entry() -> Result<()> {
+        let entry = BatchEntry::parse(
+            r#"{"url": "https://example.com/items", "method": "post", "headers": {"X-Trace": "smoke"},
+                "json": {"name": "x"}, "expect_status": 201}"#
+        )?;
+        let shared = vec![
+            ("X-Trace".to_string(), "shared".to_string()),
+            ("User-Agent".to_string(), "rusty_curl".to_string()),
+        ];
+
+        let spec = entry.into_spec(&shared)?;
+
+        assert_eq!(spec.url, "https://example.com/items");
+        assert_eq!(spec.method, CliMethod::Post);
+        assert_eq!(spec.expect_status, Some(201));
+        assert_eq!(spec.headers, vec![
+            ("X-Trace".to_string(), "smoke".to_string()),
+            ("User-Agent".to_string(), "rusty_curl".to_string()),
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Accept".to_string(), "application/json".to_string()),
+        ]);
+        match spec.body {
+            Some(RequestBody::Bytes(data)) => assert_eq!(&data[..], br#"{"name":"x"}"#),
+            _ => panic!("Expected an in-memory body"),
+        }
+
+        Ok(())
+    }
+}
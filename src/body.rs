@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures::TryStreamExt;
+use log::info;
+use tokio_util::io::ReaderStream;
+
+use crate::progress::Transfer;
+
+// Files bigger than this are streamed from disk instead of read into memory.
+// Streamed bodies can't be replayed, so requests using them are not retried.
+const STREAM_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+// A request body, either held in memory or streamed from a file.
+// The in-memory bytes are reference counted so every URL can share them.
+#[derive(Debug, Clone)]
+pub enum RequestBody {
+    Bytes(Bytes),
+    File { path: PathBuf, len: u64 },
+}
+
+impl RequestBody {
+    // Load a body argument curl style: `@-` reads stdin, `@path` reads a file,
+    // anything else is used as is.
+    pub fn load(arg: &str) -> Result<RequestBody> {
+        match arg.strip_prefix('@') {
+            Some("-") => {
+                info!("Body: reading stdin");
+                let mut data = Vec::new();
+                io::stdin().read_to_end(&mut data).context("Failed to read body from stdin")?;
+                Ok(RequestBody::Bytes(data.into()))
+            }
+            Some(path) => RequestBody::from_file(Path::new(path), STREAM_THRESHOLD),
+            None => Ok(RequestBody::from(arg)),
+        }
+    }
+
+    pub(crate) fn from_file(path: &Path, stream_threshold: u64) -> Result<RequestBody> {
+        let len = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read body file {}", path.display()))?
+            .len();
+
+        if len > stream_threshold {
+            info!("Body: streaming {} ({} bytes)", path.display(), len);
+            return Ok(RequestBody::File { path: path.to_path_buf(), len });
+        }
+
+        let mut data = Vec::with_capacity(len as usize);
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .with_context(|| format!("Failed to read body file {}", path.display()))?;
+        Ok(RequestBody::Bytes(data.into()))
+    }
+
+    // Build a reqwest body. Files are opened fresh each time so the same body
+    // can be sent to many URLs, and counted on the transfer as they're read.
+    pub async fn to_reqwest(&self, transfer: &Transfer) -> Result<reqwest::Body> {
+        match self {
+            RequestBody::Bytes(data) => Ok(reqwest::Body::from(data.clone())),
+            RequestBody::File { path, .. } => {
+                let file = tokio::fs::File::open(path)
+                    .await
+                    .with_context(|| format!("Failed to open body file {}", path.display()))?;
+                let transfer = transfer.clone();
+                let stream = ReaderStream::new(file).inspect_ok(move |chunk| transfer.advance(chunk.len()));
+                Ok(reqwest::Body::wrap_stream(stream))
+            }
+        }
+    }
+}
+
+impl From<&str> for RequestBody {
+    fn from(s: &str) -> Self {
+        RequestBody::Bytes(Bytes::copy_from_slice(s.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn load_inline_body() {
+        let body = RequestBody::load("hello world").unwrap();
+
+        match body {
+            RequestBody::Bytes(data) => assert_eq!(&data[..], b"hello world"),
+            _ => panic!("Expected an in-memory body"),
+        }
+    }
+
+    #[test]
+    fn load_file_body_is_binary_safe() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&[0xff, 0x00, 0xfe, b'a'])?;
+
+        let body = RequestBody::load(&format!("@{}", file.path().display()))?;
+
+        match body {
+            RequestBody::Bytes(data) => assert_eq!(&data[..], &[0xff, 0x00, 0xfe, b'a']),
+            _ => panic!("Expected an in-memory body"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_missing_file_fails() {
+        let result = RequestBody::load("@/no/such/file/anywhere");
+
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Failed to read body file"));
+    }
+
+    #[test]
+    fn large_file_is_streamed() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"0123456789")?;
+
+        let body = RequestBody::from_file(file.path(), 4)?;
+
+        assert!(matches!(body, RequestBody::File { len: 10, .. }));
+
+        Ok(())
+    }
+}
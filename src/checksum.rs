@@ -0,0 +1,340 @@
+use std::fmt;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::info;
+use md5::Md5;
+use reqwest::header::HeaderMap;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::AsyncReadExt;
+
+use crate::cli::Cli;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    // The names --checksum takes
+    fn from_name(name: &str) -> Option<Algorithm> {
+        match name.to_ascii_lowercase().as_str() {
+            "md5" => Some(Algorithm::Md5),
+            "sha1" => Some(Algorithm::Sha1),
+            "sha256" => Some(Algorithm::Sha256),
+            "sha512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    // The names used by the Digest and Repr-Digest headers
+    fn from_header_name(name: &str) -> Option<Algorithm> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "md5" => Some(Algorithm::Md5),
+            "sha" => Some(Algorithm::Sha1),
+            "sha-256" => Some(Algorithm::Sha256),
+            "sha-512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+
+    // Digest size in bytes
+    fn len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha512 => 64,
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+            Algorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Md5(h) => h.finalize().to_vec(),
+            Hasher::Sha1(h) => h.finalize().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+// A digest the body must have, from --checksum ALGO:HEX
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub value: Vec<u8>,
+}
+
+impl Checksum {
+    pub fn parse(s: &str) -> Result<Checksum, String> {
+        let (name, hex) = s.split_once(':').ok_or("expected ALGO:HEX, e.g. sha256:<hex>")?;
+        let algorithm = Algorithm::from_name(name)
+            .ok_or_else(|| format!("unknown algorithm '{}': use md5, sha1, sha256 or sha512", name))?;
+        let value = decode_hex(hex).ok_or_else(|| format!("'{}' isn't hex", hex))?;
+        if value.len() != algorithm.len() {
+            return Err(format!("a {} digest is {} hex digits, not {}", name, algorithm.len() * 2, hex.len()));
+        }
+        Ok(Checksum { algorithm, value })
+    }
+}
+
+// What bodies are checked against, and whether their digest is reported.
+// Digest, Content-MD5 and Repr-Digest response headers are always checked
+// when the body they describe is the one received.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DigestOptions {
+    pub checksum: Option<Checksum>,
+    // Report the digest (the --checksum algorithm, else sha256)
+    pub print: bool,
+}
+
+impl DigestOptions {
+    pub fn from_cli(cli: &Cli) -> Self {
+        DigestOptions { checksum: cli.checksum.clone(), print: cli.print_digest }
+    }
+
+    // Start hashing a body. `whole` says the response headers' digests are
+    // of exactly the bytes that will arrive: not a range, and not
+    // decompressed on the way in.
+    pub fn start(&self, headers: &HeaderMap, whole: bool) -> BodyDigest {
+        let mut expected: Vec<Expected> = self.checksum.iter()
+            .map(|checksum| Expected { algorithm: checksum.algorithm, value: checksum.value.clone(), from: "--checksum" })
+            .collect();
+        if whole {
+            expected.extend(header_digests(headers));
+        }
+        let print = self.print.then(|| self.checksum.as_ref().map_or(Algorithm::Sha256, |c| c.algorithm));
+
+        let mut hashers: Vec<(Algorithm, Hasher)> = Vec::new();
+        for algorithm in expected.iter().map(|e| e.algorithm).chain(print) {
+            if !hashers.iter().any(|(a, _)| *a == algorithm) {
+                hashers.push((algorithm, algorithm.hasher()));
+            }
+        }
+
+        BodyDigest { hashers, expected, print }
+    }
+}
+
+// The digests of a body as it streams past
+#[derive(Default)]
+pub struct BodyDigest {
+    hashers: Vec<(Algorithm, Hasher)>,
+    expected: Vec<Expected>,
+    print: Option<Algorithm>,
+}
+
+struct Expected {
+    algorithm: Algorithm,
+    value: Vec<u8>,
+    // Where it came from, for the error
+    from: &'static str,
+}
+
+impl BodyDigest {
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+    }
+
+    // Hash what's already on disk, for a resumed or segmented download
+    pub async fn update_from_file(&mut self, path: &Path) -> std::io::Result<()> {
+        if self.hashers.is_empty() {
+            return Ok(());
+        }
+
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.update(&buf[..n]);
+        }
+    }
+
+    // Check every expected digest, returning the one to report as ALGO:HEX
+    pub fn finish(self) -> Result<Option<String>, ChecksumMismatch> {
+        let digests: Vec<(Algorithm, Vec<u8>)> = self.hashers.into_iter()
+            .map(|(algorithm, hasher)| (algorithm, hasher.finalize()))
+            .collect();
+        let digest_of = |algorithm| digests.iter().find(|(a, _)| *a == algorithm).map(|(_, d)| d);
+
+        for expected in &self.expected {
+            let actual = digest_of(expected.algorithm).expect("every expected algorithm is hashed");
+            if *actual != expected.value {
+                return Err(ChecksumMismatch {
+                    from: expected.from,
+                    algorithm: expected.algorithm,
+                    expected: encode_hex(&expected.value),
+                    actual: encode_hex(actual),
+                });
+            }
+            info!("Checksum: {} {} matches", expected.from, expected.algorithm.name());
+        }
+
+        Ok(self.print.and_then(|algorithm| {
+            digest_of(algorithm).map(|digest| format!("{}:{}", algorithm.name(), encode_hex(digest)))
+        }))
+    }
+}
+
+// A body that didn't match its checksum. main exits with a code of its own
+// for these.
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    from: &'static str,
+    algorithm: Algorithm,
+    expected: String,
+    actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} mismatch ({}): expected {}, got {}", self.algorithm.name(), self.from, self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+// Digest (RFC 3230), Content-MD5 and Repr-Digest (RFC 9530) values this
+// knows the algorithm of
+fn header_digests(headers: &HeaderMap) -> Vec<Expected> {
+    let mut expected = Vec::new();
+    let mut add = |from, algorithm: Option<Algorithm>, value: &str| {
+        if let Some(algorithm) = algorithm
+            && let Ok(value) = STANDARD.decode(value.trim())
+            && value.len() == algorithm.len() {
+            expected.push(Expected { algorithm, value, from });
+        }
+    };
+
+    for (header, from) in [("digest", "Digest"), ("repr-digest", "Repr-Digest")] {
+        for value in headers.get_all(header).iter().filter_map(|v| v.to_str().ok()) {
+            for (name, value) in value.split(',').filter_map(|item| item.split_once('=')) {
+                // Repr-Digest values are byte sequences, :base64:
+                add(from, Algorithm::from_header_name(name), value.trim().trim_matches(':'));
+            }
+        }
+    }
+    if let Some(value) = headers.get("content-md5").and_then(|v| v.to_str().ok()) {
+        add("Content-MD5", Some(Algorithm::Md5), value);
+    }
+
+    expected
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn digest_of(options: &DigestOptions, headers: &HeaderMap, body: &[u8]) -> Result<Option<String>, ChecksumMismatch> {
+        let mut digest = options.start(headers, true);
+        for chunk in body.chunks(2) {
+            digest.update(chunk);
+        }
+        digest.finish()
+    }
+
+    #[test]
+    fn parses_checksums() {
+        let checksum = Checksum::parse(&format!("SHA256:{}", HELLO_SHA256)).unwrap();
+        assert_eq!(checksum.algorithm, Algorithm::Sha256);
+        assert_eq!(encode_hex(&checksum.value), HELLO_SHA256);
+
+        assert!(Checksum::parse("sha256").is_err());
+        assert!(Checksum::parse("crc32:abcd").unwrap_err().contains("unknown algorithm"));
+        assert!(Checksum::parse("md5:zz").unwrap_err().contains("isn't hex"));
+        assert!(Checksum::parse("sha1:abcd").unwrap_err().contains("40 hex digits"));
+    }
+
+    #[test]
+    fn checks_and_prints_the_checksum() {
+        let options = DigestOptions { checksum: Some(Checksum::parse(&format!("sha256:{}", HELLO_SHA256)).unwrap()), print: true };
+        assert_eq!(digest_of(&options, &HeaderMap::new(), b"hello").unwrap(), Some(format!("sha256:{}", HELLO_SHA256)));
+
+        let e = digest_of(&options, &HeaderMap::new(), b"hellO").unwrap_err();
+        assert!(e.to_string().starts_with(&format!("sha256 mismatch (--checksum): expected {}, got ", HELLO_SHA256)), "{}", e);
+    }
+
+    #[test]
+    fn print_defaults_to_sha256() {
+        let options = DigestOptions { checksum: None, print: true };
+        assert_eq!(digest_of(&options, &HeaderMap::new(), b"hello").unwrap(), Some(format!("sha256:{}", HELLO_SHA256)));
+        assert_eq!(digest_of(&DigestOptions::default(), &HeaderMap::new(), b"hello").unwrap(), None);
+    }
+
+    #[test]
+    fn checks_digest_headers() {
+        let mut headers = HeaderMap::new();
+        // md5 and sha-256 of "hello"
+        headers.insert("content-md5", HeaderValue::from_static("XUFAKrxLKna5cZ2REBfFkg=="));
+        headers.insert("repr-digest", HeaderValue::from_static("sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:, unknown=:AAAA:"));
+        headers.insert("digest", HeaderValue::from_static("SHA-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="));
+
+        assert!(digest_of(&DigestOptions::default(), &headers, b"hello").is_ok());
+
+        let e = digest_of(&DigestOptions::default(), &headers, b"jello").unwrap_err();
+        assert_eq!(e.from, "Digest");
+
+        // A range or decompressed body isn't what the headers describe
+        assert!(DigestOptions::default().start(&headers, false).finish().is_ok());
+    }
+}
@@ -1,15 +1,122 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
 use anyhow::Result;
 use clap::{Parser as ClapParser, ValueEnum};
 use log::{warn, error};
-
-// Define an enum for a specific argument's possible values
-#[derive(Default, Debug, Clone, ValueEnum, PartialEq)]
+use reqwest::Method;
+
+use crate::batch::BatchEntry;
+use crate::checksum::Checksum;
+use crate::cookies::CookieOptions;
+use crate::form;
+use crate::netrc::Netrc;
+use crate::proxy::ProxyOptions;
+use crate::sigv4::{AwsCredentials, AwsScope};
+use crate::tls::{TlsOptions, TlsVersion, client_config, parse_tls_version};
+use crate::output::WriteOut;
+use crate::urls;
+
+// The request method. The RFC 9110 methods are matched case-insensitively,
+// anything else that is a valid token is sent exactly as given (e.g. PURGE).
+#[derive(Default, Debug, Clone, PartialEq)]
 pub enum CliMethod {
     #[default]
     Get,
+    Head,
     Post,
     Put,
-    Delete
+    Patch,
+    Delete,
+    Options,
+    Connect,
+    Trace,
+    // Any other method, already checked to be a valid token
+    Custom(Method),
+}
+
+impl CliMethod {
+    pub fn as_str(&self) -> &str {
+        match self {
+            CliMethod::Get => "GET",
+            CliMethod::Head => "HEAD",
+            CliMethod::Post => "POST",
+            CliMethod::Put => "PUT",
+            CliMethod::Patch => "PATCH",
+            CliMethod::Delete => "DELETE",
+            CliMethod::Options => "OPTIONS",
+            CliMethod::Connect => "CONNECT",
+            CliMethod::Trace => "TRACE",
+            CliMethod::Custom(method) => method.as_str(),
+        }
+    }
+
+    // Whether a request body has defined semantics for this method. GET, HEAD,
+    // DELETE and CONNECT bodies have none, and TRACE must not carry one.
+    pub fn allows_body(&self) -> bool {
+        !matches!(
+            self,
+            CliMethod::Get | CliMethod::Head | CliMethod::Delete | CliMethod::Connect | CliMethod::Trace
+        )
+    }
+
+    pub fn to_method(&self) -> Method {
+        match self {
+            CliMethod::Get => Method::GET,
+            CliMethod::Head => Method::HEAD,
+            CliMethod::Post => Method::POST,
+            CliMethod::Put => Method::PUT,
+            CliMethod::Patch => Method::PATCH,
+            CliMethod::Delete => Method::DELETE,
+            CliMethod::Options => Method::OPTIONS,
+            CliMethod::Connect => Method::CONNECT,
+            CliMethod::Trace => Method::TRACE,
+            CliMethod::Custom(method) => method.clone(),
+        }
+    }
+}
+
+impl fmt::Display for CliMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CliMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let method = match s.to_ascii_uppercase().as_str() {
+            "GET" => CliMethod::Get,
+            "HEAD" => CliMethod::Head,
+            "POST" => CliMethod::Post,
+            "PUT" => CliMethod::Put,
+            "PATCH" => CliMethod::Patch,
+            "DELETE" => CliMethod::Delete,
+            "OPTIONS" => CliMethod::Options,
+            "CONNECT" => CliMethod::Connect,
+            "TRACE" => CliMethod::Trace,
+            // reqwest only takes tokens
+            _ => Method::from_bytes(s.as_bytes())
+                .map(CliMethod::Custom)
+                .map_err(|_| format!("invalid method `{}`: must be an HTTP token", s))?,
+        };
+        Ok(method)
+    }
+}
+
+// How results are written: the human readable layout, a single JSON array,
+// or one JSON object per line
+#[derive(Default, Debug, Clone, Copy, ValueEnum, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+    Ndjson,
 }
 
 #[derive(ClapParser, Default)]
@@ -19,32 +126,288 @@ pub struct Cli {
     #[arg(short, long, value_name = "FILE")]
     pub output: Option<String>,
 
-    // Sets a body
+    // Sets a body (@file reads a file, @- reads stdin)
     #[arg(short, long, value_name = "BODY")]
     pub body: Option<String>,
 
-    // Sets a json
+    // Sets a json (@file reads a file, @- reads stdin). Sends JSON Content-Type
+    // and Accept headers unless they're given with -H
     #[arg(short, long, value_name = "JSON")]
     pub json: Option<String>,
 
-    // Sets a form
-    #[arg(short, long, value_name = "FORM")]
-    pub form: Option<String>,
+    // Add a multipart form field (e.g. -F name=value, -F "file=@photo.png;type=image/png")
+    #[arg(short = 'F', long, short_alias = 'f', value_name = "NAME=VALUE")]
+    pub form: Vec<String>,
+
+    // Add a urlencoded form field (name=content, name@file, =content or content)
+    #[arg(long = "data-urlencode", value_name = "DATA")]
+    pub data_urlencode: Vec<String>,
 
     // Add headers (e.g. -H "Accept: application/json")
     #[arg(short = 'H', long = "header", value_parser = parse_key_val, num_args = 0..)]
     pub headers: Vec<(String, String)>,
 
+    // Basic auth credentials; prompts for the password when only the user is given
+    #[arg(short, long, value_name = "USER[:PASSWORD]")]
+    pub user: Option<String>,
+
+    // Use Digest auth with the --user credentials instead of Basic
+    #[arg(long, requires = "user")]
+    pub digest: bool,
+
+    // Send a bearer token
+    #[arg(long, value_name = "TOKEN")]
+    pub bearer: Option<String>,
+
+    // Read the bearer token from a file, keeping it out of shell history
+    #[arg(long, value_name = "FILE")]
+    pub bearer_file: Option<String>,
+
+    // Sign requests with AWS Signature Version 4, e.g. aws:us-east-1:execute-api.
+    // Credentials come from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY or ~/.aws/credentials
+    #[arg(long, value_name = "PROVIDER:REGION:SERVICE", value_parser = AwsScope::from_str)]
+    pub aws_sigv4: Option<AwsScope>,
+
+    // Get an OAuth2 client-credentials token from this URL and send it with
+    // every request, refreshing it as needed. Tokens are cached on disk.
+    #[arg(long, value_name = "URL", requires = "oauth2_client_id")]
+    pub oauth2_token_url: Option<String>,
+
+    // OAuth2 client id
+    #[arg(long, value_name = "ID", requires = "oauth2_token_url")]
+    pub oauth2_client_id: Option<String>,
+
+    // OAuth2 client secret
+    #[arg(long, value_name = "SECRET", requires = "oauth2_token_url")]
+    pub oauth2_client_secret: Option<String>,
+
+    // Read the OAuth2 client secret from a file, keeping it out of shell history
+    #[arg(long, value_name = "FILE", requires = "oauth2_token_url", conflicts_with = "oauth2_client_secret")]
+    pub oauth2_client_secret_file: Option<String>,
+
+    // Space separated OAuth2 scopes to ask for
+    #[arg(long, value_name = "SCOPE", requires = "oauth2_token_url")]
+    pub oauth2_scope: Option<String>,
+
+    // Use credentials from ~/.netrc for hosts without --user or --bearer
+    #[arg(long, conflicts_with = "netrc_optional")]
+    pub netrc: bool,
+
+    // Like --netrc, but carry on without it if ~/.netrc doesn't exist
+    #[arg(long)]
+    pub netrc_optional: bool,
+
+    // Use credentials from this netrc file instead of ~/.netrc
+    #[arg(long, value_name = "FILE")]
+    pub netrc_file: Option<String>,
+
+    // Trust only the CA certificates in this PEM file instead of the built in ones
+    #[arg(long, value_name = "FILE")]
+    pub cacert: Option<String>,
+
+    // Trust only the CA certificates in the PEM files in this directory
+    #[arg(long, value_name = "DIR")]
+    pub capath: Option<String>,
+
+    // Client certificate: a PEM file, or a PKCS#12 (.p12/.pfx) file with an
+    // optional :password
+    #[arg(long, value_name = "FILE[:PASSWORD]")]
+    pub cert: Option<String>,
+
+    // Private key for a PEM client certificate, if it isn't in the --cert file
+    #[arg(long, value_name = "FILE", requires = "cert")]
+    pub key: Option<String>,
+
+    // Don't verify the server's certificate
+    #[arg(short = 'k', long)]
+    pub insecure: bool,
+
+    // Oldest TLS version to allow: 1.2 or 1.3
+    #[arg(long, value_name = "VERSION", value_parser = parse_tls_version)]
+    pub tls_min_version: Option<TlsVersion>,
+
+    // Only talk to servers with this public key: sha256//<base64> hashes
+    // separated by ';', or a PEM or DER public key file
+    #[arg(long, value_name = "HASHES|FILE")]
+    pub pinnedpubkey: Option<String>,
+
+    // Send requests through this proxy: http://, https://, socks5:// (which
+    // looks hosts up locally) or socks5h:// (which leaves that to the proxy).
+    // Without it, http_proxy, https_proxy and all_proxy are used.
+    #[arg(short = 'x', long, value_name = "[SCHEME://][USER:PASSWORD@]HOST[:PORT]")]
+    pub proxy: Option<String>,
+
+    // Proxy credentials, in place of any in the proxy URL
+    #[arg(short = 'U', long, value_name = "USER:PASSWORD")]
+    pub proxy_user: Option<String>,
+
+    // Comma separated hosts to reach without the proxy, replacing NO_PROXY
+    // ('*' for all of them)
+    #[arg(long, value_name = "HOSTS")]
+    pub noproxy: Option<String>,
+
+    // Follow redirects, recording each hop
+    #[arg(short = 'L', long)]
+    pub location: bool,
+
+    // Like --location, and send Authorization and Cookie headers on to other
+    // hosts too
+    #[arg(long)]
+    pub location_trusted: bool,
+
+    // Maximum number of redirects to follow
+    #[arg(long, value_name = "N", default_value_t = 50)]
+    pub max_redirs: u32,
+
+    // Keep POST as POST when following a 301 (it becomes GET otherwise)
+    #[arg(long)]
+    pub post301: bool,
+
+    // Keep POST as POST when following a 302
+    #[arg(long)]
+    pub post302: bool,
+
+    // Keep POST as POST when following a 303
+    #[arg(long)]
+    pub post303: bool,
+
+    // Send cookies: name=value pairs, or a Netscape cookie file to load
+    // (-b is --body here, so there's no short form)
+    #[arg(long, value_name = "DATA|FILE")]
+    pub cookie: Vec<String>,
+
+    // Write every cookie loaded or received to this Netscape cookie file
+    // after the run
+    #[arg(short = 'c', long, value_name = "FILE")]
+    pub cookie_jar: Option<String>,
+
+    // Send cookies set by one request with the later ones, e.g. log in and
+    // then call the API. Requests go one at a time.
+    #[arg(long)]
+    pub session: bool,
+
+    // Save each body to a file named after the last part of its URL
+    // instead of writing it to the output
+    #[arg(short = 'O', long)]
+    pub remote_name: bool,
+
+    // Save the body to this file instead of writing it to the output. Given
+    // once per URL, in the same order as the URLs (-o is the report output).
+    #[arg(long, value_name = "FILE")]
+    pub save: Vec<String>,
+
+    // Resume downloads from where an earlier run stopped; '-' is the only
+    // offset, as with curl -C -
+    #[arg(short = 'C', long, value_name = "-", value_parser = ["-"])]
+    pub continue_at: Option<String>,
+
+    // Download in this many byte ranges at once, when the server takes ranges
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..=64))]
+    pub segments: u64,
+
+    // Check the body of the one URL against a digest: md5, sha1, sha256 or
+    // sha512, e.g. sha256:<hex>. A mismatch fails the request and exits with 4.
+    #[arg(long, value_name = "ALGO:HEX", value_parser = Checksum::parse)]
+    pub checksum: Option<Checksum>,
+
+    // Report each body's digest (the --checksum algorithm, else sha256)
+    #[arg(long)]
+    pub print_digest: bool,
+
     // Choose a method
-    #[arg(short, long, value_enum, default_value_t = CliMethod::Get)]
+    #[arg(short, long, value_parser = CliMethod::from_str, default_value_t = CliMethod::Get)]
     pub method: CliMethod,
 
     // Print latency
     #[arg(short, long, value_name = "LATENCY")]
     pub latency: bool,
 
+    // Like curl -v, less the TLS details reqwest doesn't expose
+    #[arg(
+        short,
+        long,
+        help = "Trace connections, the server certificate and headers to stderr. \
+                The TLS version, cipher suite and ALPN protocol are left out: \
+                reqwest doesn't expose them (see --tls-probe)"
+    )]
+    pub verbose: bool,
+
+    // The TLS details -v can't show, from a second handshake
+    #[arg(
+        long,
+        help = "With -v, shake hands with each https host again on a separate \
+                connection to show its TLS version, cipher suite, ALPN protocol \
+                and whole chain. It may reach a different server than the request"
+    )]
+    pub tls_probe: bool,
+
+    // Don't show progress on stderr
+    #[arg(short, long)]
+    pub silent: bool,
+
+    // Print bodies as received (don't pretty-print JSON)
+    #[arg(long)]
+    pub raw: bool,
+
+    // Choose the output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output_format: OutputFormat,
+
+    // Write one line per request from a template, e.g. '%{http_code} %{time_total}\n'
+    // (@file reads the template from a file)
+    #[arg(short = 'w', long, value_name = "FORMAT")]
+    pub write_out: Option<String>,
+
+    // Limit on each whole request, e.g. 120, 2m or 500ms [default: 10s]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub max_time: Option<Duration>,
+
+    // Limit on connecting, e.g. 5 or 500ms
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub connect_timeout: Option<Duration>,
+
+    // Number of retries for transient failures, 0 turns retries off
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub retry: u32,
+
+    // Cap on the backoff between retries, e.g. 30s
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub retry_max_delay: Option<Duration>,
+
+    // Retry on these statuses instead of the defaults (e.g. --retry-on 429,503)
+    #[arg(long, value_name = "STATUS", value_delimiter = ',', value_parser = clap::value_parser!(u16).range(100..600))]
+    pub retry_on: Vec<u16>,
+
+    // Maximum number of requests in flight at once
+    #[arg(long, value_name = "N", default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+    pub parallel: u64,
+
+    // Maximum number of requests in flight at once to any one host
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub parallel_host: Option<u64>,
+
+    // Write results as requests finish instead of in the order given
+    #[arg(long)]
+    pub completion_order: bool,
+
+    // Maximum request rate, e.g. 50/s, 600/m or 10 (per second)
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub rate: Option<f64>,
+
+    // Read URLs from a file, one per line (blank lines and # comments are skipped)
+    #[arg(long, value_name = "FILE")]
+    pub url_file: Option<String>,
+
+    // Read URLs from stdin, one per line (blank lines and # comments are skipped)
+    #[arg(long)]
+    pub urls_from_stdin: bool,
+
+    // Read requests from a JSON Lines file, each with its own method, headers and body
+    #[arg(long, value_name = "FILE")]
+    pub batch: Option<String>,
+
     // One or more URLs to fetch
-    #[arg(value_name = "URL", required = true)]
+    #[arg(value_name = "URL", required_unless_present_any = ["url_file", "urls_from_stdin", "batch"])]
     pub urls: Vec<String>,
 }
 
@@ -90,38 +453,227 @@ pub fn validate_cli(cli: &Cli) -> ValidationReport {
 
     // Check that urls are well formed
     for url in cli.urls.iter() {
-        if !valid_url(&url) {
+        if !valid_url(url) {
             report.errors.push(format!("Invalid URL {}: must start with http:// or https://", url));
         }
     }
 
-    // Warn if there's a body/json/form on a GET or DELETE
-    if ((cli.method == CliMethod::Get) || (cli.method == CliMethod::Delete)) &&
-        (cli.body.is_some() || cli.json.is_some() || cli.form.is_some()) {
-        report.warnings.push("Body not allowed for GET or DELETE".to_string());
+    // Check the URL file a line at a time. Stdin can only be read once, so its
+    // URLs are checked as they're read.
+    if let Some(path) = &cli.url_file {
+        validate_url_file(path, &mut report);
     }
 
-    // Check if there's only one or zero of body, json, form
-    if [cli.body.as_ref(), cli.json.as_ref(), cli.form.as_ref()]
-        .iter()
-        .filter(|opt| opt.is_some())
-        .count() > 1
-    {
+    if let Some(path) = &cli.batch {
+        validate_batch_file(path, &mut report);
+    }
+
+    if cli.urls_from_stdin && [&cli.body, &cli.json].into_iter().flatten().any(|arg| arg == "@-") {
+        report.errors.push("Can't read both URLs and a body from stdin".to_string());
+    }
+
+    let has_body = [cli.body.is_some(), cli.json.is_some(), !cli.form.is_empty(), !cli.data_urlencode.is_empty()];
+
+    // Warn if there's a body/json/form on a method that doesn't take one
+    if !cli.method.allows_body() && has_body.contains(&true) {
+        report.warnings.push(format!("Body not allowed for {}", cli.method));
+    }
+
+    // Check if there's only one or zero of body, json, form (multipart or urlencoded)
+    if has_body.iter().filter(|present| **present).count() > 1 {
         report.errors.push("Can't have more than one of body, json, and form".into());
     }
 
+    // The multipart body only parses with the boundary it was built with
+    if !cli.form.is_empty() && cli.headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("Content-Type")) {
+        report.errors.push("-F sets its own Content-Type (with the multipart boundary): drop the -H".into());
+    }
+
+    // Check that form fields are well formed and their files exist
+    for field in &cli.form {
+        if let Err(e) = form::validate_part(field) {
+            report.errors.push(e);
+        }
+    }
+    for field in &cli.data_urlencode {
+        if let Err(e) = form::validate_urlencoded(field) {
+            report.errors.push(e);
+        }
+    }
+
+    // Only one way to authenticate
+    let auth = [cli.user.is_some(), cli.bearer.is_some(), cli.bearer_file.is_some(), cli.aws_sigv4.is_some()];
+    if auth.iter().filter(|present| **present).count() > 1 {
+        report.errors.push("Can't have more than one of user, bearer, bearer-file, and aws-sigv4".into());
+    }
+    if cli.aws_sigv4.is_some() && let Err(e) = AwsCredentials::load() {
+        report.errors.push(format!("{:#}", e));
+    }
+    if let Some(url) = &cli.oauth2_token_url {
+        if !valid_url(url) {
+            report.errors.push(format!("Invalid OAuth2 token URL {}: must start with http:// or https://", url));
+        }
+        if auth.contains(&true) {
+            report.errors.push("Can't have OAuth2 with user, bearer, bearer-file, or aws-sigv4".into());
+        }
+    }
+    if let Some(path) = &cli.oauth2_client_secret_file && !Path::new(path).is_file() {
+        report.errors.push(format!("OAuth2 client secret file {} not found", path));
+    }
+    if cli.digest && cli.user.is_none() {
+        report.errors.push("--digest needs --user".into());
+    }
+    if let Some(path) = &cli.bearer_file && !Path::new(path).is_file() {
+        report.errors.push(format!("Bearer token file {} not found", path));
+    }
+    if auth.contains(&true) && cli.headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("Authorization")) {
+        report.warnings.push("-H Authorization replaces --user, --bearer and --aws-sigv4".to_string());
+    }
+
+    // Read the netrc file now so a broken one stops the run before any request
+    if let Err(e) = Netrc::from_cli(cli) {
+        report.errors.push(format!("{:#}", e));
+    }
+
+    if cli.save.len() > cli.urls.len() {
+        report.errors.push(format!("{} --save files for {} URLs", cli.save.len(), cli.urls.len()));
+    }
+    if cli.continue_at.is_some() && !cli.remote_name && cli.save.is_empty() {
+        report.errors.push("--continue-at needs -O or --save".into());
+    }
+    if cli.segments > 1 && !cli.remote_name && cli.save.is_empty() {
+        report.errors.push("--segments needs -O or --save".into());
+    }
+    // One digest can only describe one body
+    if cli.checksum.is_some()
+        && (cli.urls.len() > 1 || cli.url_file.is_some() || cli.urls_from_stdin || cli.batch.is_some())
+    {
+        report.errors.push("--checksum is for a single URL, not a list of them".into());
+    }
+
+    // Load the cookie files now so a bad one stops the run before any request
+    if let Err(e) = CookieOptions::from_cli(cli) {
+        report.errors.push(format!("{:#}", e));
+    }
+
+    // Check the proxy URLs from -x and the environment
+    if let Err(e) = ProxyOptions::from_cli(cli).routes() {
+        report.errors.push(format!("{:#}", e));
+    }
+
+    // Load the certificates and keys now so a bad one stops the run before any request
+    if let Err(e) = TlsOptions::from_cli(cli).and_then(|tls| client_config(&tls)) {
+        report.errors.push(format!("{:#}", e));
+    }
+
+    // Check that any @file bodies exist
+    for arg in [&cli.body, &cli.json].into_iter().flatten() {
+        if let Some(path) = arg.strip_prefix('@') && path != "-" && !Path::new(path).is_file() {
+            report.errors.push(format!("Body file {} not found", path));
+        }
+    }
+
     // Check if there's json, that it's valid
     if let Some(json) = &cli.json {
-        // Validate the JSON
-        if let Err(e) = serde_json::from_str::<serde_json::Value>(json) {
+        // Validate the JSON, reading files without loading them whole.
+        // Stdin can only be read once, so it's sent unchecked.
+        let parsed = match json.strip_prefix('@') {
+            Some("-") => Ok(()),
+            Some(path) => match File::open(path) {
+                Ok(file) => serde_json::from_reader::<_, serde_json::Value>(BufReader::new(file)).map(|_| ()),
+                Err(_) => Ok(()), // already reported as not found
+            },
+            None => serde_json::from_str::<serde_json::Value>(json).map(|_| ()),
+        };
+        if let Err(e) = parsed {
             report.errors.push(format!("JSON is not valid: {}", e));
         }
     }
 
+    // Check the write-out template
+    if let Some(template) = &cli.write_out {
+        match template.strip_prefix('@') {
+            Some(path) => {
+                if !Path::new(path).is_file() {
+                    report.errors.push(format!("Write-out file {} not found", path));
+                }
+            }
+            None => {
+                if let Err(e) = WriteOut::parse(template) {
+                    report.errors.push(e);
+                }
+            }
+        }
+
+        if cli.output_format != OutputFormat::Human {
+            report.warnings.push("--write-out replaces --output-format".to_string());
+        }
+    }
+
+    if cli.tls_probe && !cli.verbose {
+        report.warnings.push("--tls-probe only shows with -v".to_string());
+    }
+
     // Return the generated report
     report
 }
 
+fn validate_url_file(path: &str, report: &mut ValidationReport) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            report.errors.push(format!("Can't read URL file {}: {}", path, e));
+            return;
+        }
+    };
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                report.errors.push(format!("Can't read URL file {} at line {}: {}", path, i + 1, e));
+                return;
+            }
+        };
+
+        if let Some(url) = urls::parse_line(&line)
+            && !valid_url(url) {
+            report.errors.push(format!("Invalid URL {} at {}:{}: must start with http:// or https://", url, path, i + 1));
+        }
+    }
+}
+
+// Check every batch entry, reporting problems with their line numbers
+fn validate_batch_file(path: &str, report: &mut ValidationReport) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            report.errors.push(format!("Can't read batch file {}: {}", path, e));
+            return;
+        }
+    };
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                report.errors.push(format!("Can't read batch file {} at line {}: {}", path, i + 1, e));
+                return;
+            }
+        };
+
+        let Some(line) = urls::parse_line(&line) else { continue };
+        match BatchEntry::parse(line) {
+            Ok(entry) => {
+                let (errors, warnings) = entry.validate();
+                report.errors.extend(errors.into_iter().map(|e| format!("{} at {}:{}", e, path, i + 1)));
+                report.warnings.extend(warnings.into_iter().map(|w| format!("{} at {}:{}", w, path, i + 1)));
+            }
+            Err(e) => report.errors.push(format!("Invalid batch entry at {}:{}: {}", path, i + 1, e)),
+        }
+    }
+}
+
 fn parse_key_val(s: &str) -> Result<(String, String), String> {
     let pos = s.find(':').ok_or_else(|| format!("invalid KEY:VALUE: no `:` found in `{}`", s))?;
     let key = s[..pos].trim().to_string();
@@ -129,7 +681,53 @@ fn parse_key_val(s: &str) -> Result<(String, String), String> {
     Ok((key, value))
 }
 
-fn valid_url(url: &str) -> bool {
+// A duration in seconds, optionally with a unit: 500ms, 1.5s, 2m
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, scale) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else {
+        (s, 1.0)
+    };
+
+    number.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n >= 0.0)
+        .and_then(|n| Duration::try_from_secs_f64(n * scale).ok())
+        .ok_or_else(|| format!("invalid duration `{}`: expected e.g. 10, 1.5s, 500ms or 2m", s))
+}
+
+// Slowest rate taken: one request an hour
+const MIN_RATE: f64 = 1.0 / 3600.0;
+
+// A request rate as requests per second: N, N/s, N/m or N/h
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let (number, unit) = s.trim().split_once('/').unwrap_or((s.trim(), "s"));
+    let seconds = match unit {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("invalid rate `{}`: unit must be s, m or h", s)),
+    };
+
+    let rate = number.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n > 0.0)
+        .map(|n| n / seconds)
+        .ok_or_else(|| format!("invalid rate `{}`: expected e.g. 50/s", s))?;
+    if rate < MIN_RATE {
+        return Err(format!("invalid rate `{}`: the slowest is 1/h", s));
+    }
+    Ok(rate)
+}
+
+pub fn valid_url(url: &str) -> bool {
     url.starts_with("http://") || url.starts_with("https://")
 }
 
@@ -144,7 +742,7 @@ mod tests {
 
         let report = validate_cli(&cli);
 
-        assert_eq!(report.has_errors(), false);
+        assert!(!report.has_errors());
 
         Ok(())
     }
@@ -156,7 +754,7 @@ mod tests {
 
         let report = validate_cli(&cli);
 
-        assert_eq!(report.has_errors(), true);
+        assert!(report.has_errors());
 
         assert!(
             report.errors.iter().any(|e| e.contains("Invalid URL")),
@@ -166,6 +764,162 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_validate_cli_url_file_line_numbers() -> Result<()> {
+        use std::io::Write;
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "# smoke test urls")?;
+        writeln!(file, "https://example.com/ok")?;
+        writeln!(file)?;
+        writeln!(file, "ftp://example.com/bad")?;
+        let path = file.path().display().to_string();
+
+        let cli = Cli { url_file: Some(path.clone()), ..Default::default() };
+
+        let report = validate_cli(&cli);
+
+        assert_eq!(report.errors, vec![
+            format!("Invalid URL ftp://example.com/bad at {}:4: must start with http:// or https://", path),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_batch_file_line_numbers() -> Result<()> {
+        use std::io::Write;
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "# smoke test requests")?;
+        writeln!(file, r#"{{"url": "https://example.com/ok", "method": "POST", "json": {{"a": 1}}}}"#)?;
+        writeln!(file, r#"{{"url": "https://example.com/get", "body": "ignored"}}"#)?;
+        writeln!(file, r#"{{"url": "https://example.com/typo", "expect": 200}}"#)?;
+        let path = file.path().display().to_string();
+
+        let cli = Cli { batch: Some(path.clone()), ..Default::default() };
+
+        let report = validate_cli(&cli);
+
+        assert_eq!(report.warnings, vec![format!("Body not allowed for GET at {}:3", path)]);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].starts_with(&format!("Invalid batch entry at {}:4: unknown field `expect`", path)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_auth() -> Result<()> {
+        let cli = Cli {
+            user: Some("alice:secret".to_string()),
+            bearer: Some("token".to_string()),
+            digest: true,
+            bearer_file: Some("/no/such/token".to_string()),
+            headers: vec![("authorization".to_string(), "Basic abc".to_string())],
+            ..Default::default()
+        };
+
+        let report = validate_cli(&cli);
+
+        assert_eq!(report.errors, vec![
+            "Can't have more than one of user, bearer, bearer-file, and aws-sigv4".to_string(),
+            "Bearer token file /no/such/token not found".to_string(),
+        ]);
+        assert_eq!(report.warnings, vec!["-H Authorization replaces --user, --bearer and --aws-sigv4".to_string()]);
+
+        let cli = Cli { digest: true, ..Default::default() };
+        assert_eq!(validate_cli(&cli).errors, vec!["--digest needs --user".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_oauth2() -> Result<()> {
+        let cli = Cli {
+            oauth2_token_url: Some("ftp://auth.example.com/token".to_string()),
+            oauth2_client_id: Some("client".to_string()),
+            oauth2_client_secret_file: Some("/no/such/secret".to_string()),
+            bearer: Some("token".to_string()),
+            ..Default::default()
+        };
+
+        let report = validate_cli(&cli);
+
+        assert_eq!(report.errors, vec![
+            "Invalid OAuth2 token URL ftp://auth.example.com/token: must start with http:// or https://".to_string(),
+            "Can't have OAuth2 with user, bearer, bearer-file, or aws-sigv4".to_string(),
+            "OAuth2 client secret file /no/such/secret not found".to_string(),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_netrc_file() -> Result<()> {
+        use std::io::Write;
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "machine api.example.com login ci password")?;
+        let path = file.path().display().to_string();
+
+        let cli = Cli { netrc_file: Some(path.clone()), ..Default::default() };
+
+        let report = validate_cli(&cli);
+
+        assert_eq!(report.errors, vec![format!("Invalid netrc file {}: missing value after password", path)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_cookie_file() {
+        // name=value is a cookie, anything else a file
+        let cli = Cli { cookie: vec!["sid=abc".to_string(), "/no/such/cookies.txt".to_string()], ..Default::default() };
+
+        let report = validate_cli(&cli);
+
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].starts_with("Can't read cookie file /no/such/cookies.txt"));
+    }
+
+    #[test]
+    fn test_validate_cli_tls() {
+        let cli = Cli { cacert: Some("/no/such/ca.pem".to_string()), ..Default::default() };
+        let report = validate_cli(&cli);
+        assert_eq!(report.errors, vec!["Can't read certificate file /no/such/ca.pem: No such file or directory (os error 2)".to_string()]);
+
+        let cli = Cli { pinnedpubkey: Some("sha256//nope".to_string()), ..Default::default() };
+        let report = validate_cli(&cli);
+        assert_eq!(report.errors, vec!["Invalid pinned key `sha256//nope`: not a base64 SHA-256 hash".to_string()]);
+    }
+
+    #[test]
+    fn test_validate_cli_missing_url_file() -> Result<()> {
+        let cli = Cli { url_file: Some("/no/such/url/file".to_string()), ..Default::default() };
+
+        let report = validate_cli(&cli);
+
+        assert!(report.errors.iter().any(|e| e.contains("Can't read URL file /no/such/url/file")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_stdin_urls_and_body() -> Result<()> {
+        let cli = Cli {
+            urls_from_stdin: true,
+            method: CliMethod::Post,
+            body: Some("@-".to_string()),
+            ..Default::default()
+        };
+
+        let report = validate_cli(&cli);
+
+        assert!(report.errors.iter().any(|e| e.contains("Can't read both URLs and a body from stdin")));
+
+        Ok(())
+    }
+
     #[test]
     fn test_validate_cli_get_body() -> Result<()> {
         let mut cli = Cli::default();   // all fields defaulted
@@ -174,7 +928,7 @@ mod tests {
 
         let report = validate_cli(&cli);
 
-        assert_eq!(report.has_warnings(), true);
+        assert!(report.has_warnings());
 
         assert!(
             report.warnings.iter().any(|e| e.contains("Body not allowed")),
@@ -193,7 +947,7 @@ mod tests {
 
         let report = validate_cli(&cli);
 
-        assert_eq!(report.has_warnings(), true);
+        assert!(report.has_warnings());
 
         assert!(
             report.warnings.iter().any(|e| e.contains("Body not allowed")),
@@ -203,6 +957,37 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_validate_cli_head_body() -> Result<()> {
+        let mut cli = Cli::default();   // all fields defaulted
+        cli.urls.push("https://example.com".to_string());
+        cli.method = CliMethod::Head;
+        cli.body = Some("hello world".to_string());
+
+        let report = validate_cli(&cli);
+
+        assert!(
+            report.warnings.iter().any(|e| e.contains("Body not allowed for HEAD")),
+            "Expected an warning containing 'Body not allowed for HEAD'"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_patch_body() -> Result<()> {
+        let mut cli = Cli::default();   // all fields defaulted
+        cli.urls.push("https://example.com".to_string());
+        cli.method = CliMethod::Patch;
+        cli.body = Some("hello world".to_string());
+
+        let report = validate_cli(&cli);
+
+        assert!(!report.has_warnings());
+
+        Ok(())
+    }
+
     #[test]
     fn test_validate_cli_json_and_body() -> Result<()> {
         let mut cli = Cli::default();   // all fields defaulted
@@ -213,7 +998,7 @@ mod tests {
 
         let report = validate_cli(&cli);
 
-        assert_eq!(report.has_errors(), true);
+        assert!(report.has_errors());
 
         assert!(
             report.errors.iter().any(|e| e.contains("Can't have more than one of body, json, and form")),
@@ -229,11 +1014,11 @@ mod tests {
         cli.urls.push("https://example.com".to_string());
         cli.method = CliMethod::Post;
         cli.body = Some("some body".to_string());
-        cli.form = Some("some form".to_string());
+        cli.form = vec!["some=form".to_string()];
 
         let report = validate_cli(&cli);
 
-        assert_eq!(report.has_errors(), true);
+        assert!(report.has_errors());
 
         assert!(
             report.errors.iter().any(|e| e.contains("Can't have more than one of body, json, and form")),
@@ -243,6 +1028,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_validate_cli_form_and_content_type() {
+        let mut cli = Cli::default();   // all fields defaulted
+        cli.urls.push("https://example.com".to_string());
+        cli.method = CliMethod::Post;
+        cli.form = vec!["some=form".to_string()];
+        cli.headers = vec![("content-type".to_string(), "multipart/form-data".to_string())];
+
+        let report = validate_cli(&cli);
+
+        assert!(
+            report.errors.iter().any(|e| e.contains("-F sets its own Content-Type")),
+            "Expected an error containing '-F sets its own Content-Type'"
+        );
+    }
+
     #[test]
     fn test_validate_cli_valid_json() -> Result<()> {
         let mut cli = Cli::default();   // all fields defaulted
@@ -252,7 +1053,7 @@ mod tests {
 
         let report = validate_cli(&cli);
 
-        assert_eq!(report.has_errors(), true);
+        assert!(report.has_errors());
 
         assert!(
             report.errors.iter().any(|e| e.contains("JSON is not valid")),
@@ -262,6 +1063,135 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_validate_cli_form_and_urlencoded() -> Result<()> {
+        let mut cli = Cli::default();   // all fields defaulted
+        cli.urls.push("https://example.com".to_string());
+        cli.method = CliMethod::Post;
+        cli.form = vec!["some=form".to_string()];
+        cli.data_urlencode = vec!["some=data".to_string()];
+
+        let report = validate_cli(&cli);
+
+        assert!(
+            report.errors.iter().any(|e| e.contains("Can't have more than one of body, json, and form")),
+            "Expected an error containing 'Can't have more than one of body, json, and form'"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_bad_form_fields() -> Result<()> {
+        let mut cli = Cli::default();   // all fields defaulted
+        cli.urls.push("https://example.com".to_string());
+        cli.method = CliMethod::Post;
+        cli.form = vec!["novalue".to_string(), "file=@/no/such/form/file".to_string()];
+
+        let report = validate_cli(&cli);
+
+        assert!(report.errors.iter().any(|e| e.contains("Invalid form field `novalue`")));
+        assert!(report.errors.iter().any(|e| e.contains("Form file /no/such/form/file not found")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_missing_body_file() -> Result<()> {
+        let mut cli = Cli::default();   // all fields defaulted
+        cli.urls.push("https://example.com".to_string());
+        cli.method = CliMethod::Post;
+        cli.body = Some("@/no/such/body/file".to_string());
+
+        let report = validate_cli(&cli);
+
+        assert!(
+            report.errors.iter().any(|e| e.contains("Body file /no/such/body/file not found")),
+            "Expected an error containing 'Body file /no/such/body/file not found'"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_json_file() -> Result<()> {
+        use std::io::Write;
+
+        let mut good = tempfile::NamedTempFile::new()?;
+        good.write_all(br#"{"user": "some_user"}"#)?;
+        let mut bad = tempfile::NamedTempFile::new()?;
+        bad.write_all(b"some not json")?;
+
+        let mut cli = Cli::default();   // all fields defaulted
+        cli.urls.push("https://example.com".to_string());
+        cli.method = CliMethod::Post;
+
+        cli.json = Some(format!("@{}", good.path().display()));
+        assert!(!validate_cli(&cli).has_errors());
+
+        cli.json = Some(format!("@{}", bad.path().display()));
+        let report = validate_cli(&cli);
+        assert!(
+            report.errors.iter().any(|e| e.contains("JSON is not valid")),
+            "Expected an error containing 'JSON is not valid'"
+        );
+
+        // Stdin can't be checked up front
+        cli.json = Some("@-".to_string());
+        assert!(!validate_cli(&cli).has_errors());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_write_out() -> Result<()> {
+        let mut cli = Cli::default();   // all fields defaulted
+        cli.urls.push("https://example.com".to_string());
+
+        cli.write_out = Some("%{http_code}\\n".to_string());
+        assert!(!validate_cli(&cli).has_errors());
+
+        cli.write_out = Some("%{nonsense}".to_string());
+        let report = validate_cli(&cli);
+        assert!(
+            report.errors.iter().any(|e| e.contains("Unknown write-out variable")),
+            "Expected an error containing 'Unknown write-out variable'"
+        );
+
+        cli.write_out = Some("@/no/such/write/out".to_string());
+        let report = validate_cli(&cli);
+        assert!(report.errors.iter().any(|e| e.contains("Write-out file /no/such/write/out not found")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_cli_checksum_single_url() {
+        let checksum = Checksum::parse(&format!("sha256:{}", "0".repeat(64))).unwrap();
+        let mut cli = Cli { checksum: Some(checksum), ..Default::default() };
+        cli.urls.push("https://example.com/a".to_string());
+        assert!(!validate_cli(&cli).has_errors());
+
+        let error = "--checksum is for a single URL, not a list of them";
+        cli.urls.push("https://example.com/b".to_string());
+        assert!(validate_cli(&cli).errors.iter().any(|e| e == error));
+
+        cli.urls.truncate(1);
+        cli.urls_from_stdin = true;
+        assert!(validate_cli(&cli).errors.iter().any(|e| e == error));
+    }
+
+    #[test]
+    fn test_validate_cli_tls_probe() {
+        let mut cli = Cli::default();
+        cli.urls.push("https://example.com".to_string());
+        cli.tls_probe = true;
+        assert!(validate_cli(&cli).warnings.iter().any(|w| w == "--tls-probe only shows with -v"));
+
+        cli.verbose = true;
+        assert!(validate_cli(&cli).warnings.is_empty());
+    }
+
     #[test]
     fn parse_key_val_valid_pair() {
         let input = "Content-Type: application/json";
@@ -308,11 +1238,44 @@ mod tests {
         assert_eq!(result, ("".to_string(), "".to_string())); // still valid: empty key and value
     }
 
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("120").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn parse_duration_rejects_garbage() {
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("-1").is_err());
+        assert!(parse_duration("").is_err());
+        // Too big for a Duration
+        assert!(parse_duration("1e30").is_err());
+    }
+
+    #[test]
+    fn parse_rate_units() {
+        assert_eq!(parse_rate("50/s").unwrap(), 50.0);
+        assert_eq!(parse_rate("10").unwrap(), 10.0);
+        assert_eq!(parse_rate("120/m").unwrap(), 2.0);
+        assert_eq!(parse_rate("3600/h").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn parse_rate_rejects_garbage() {
+        assert!(parse_rate("0/s").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("5/d").is_err());
+        assert!(parse_rate("1e-320/s").unwrap_err().contains("slowest"));
+    }
+
     #[test]
     fn test_report_no_errors() -> Result<()> {
         let report = ValidationReport::default();
 
-        assert_eq!(report.has_errors(), false);
+        assert!(!report.has_errors());
 
         Ok(())
     }
@@ -323,7 +1286,7 @@ mod tests {
 
         report.errors.push("Some error".to_string());
 
-        assert_eq!(report.has_errors(), true);
+        assert!(report.has_errors());
 
         Ok(())
     }
@@ -332,7 +1295,7 @@ mod tests {
     fn test_report_no_warnings() -> Result<()> {
         let report = ValidationReport::default();
 
-        assert_eq!(report.has_warnings(), false);
+        assert!(!report.has_warnings());
 
         Ok(())
     }
@@ -343,7 +1306,7 @@ mod tests {
 
         report.warnings.push("Some warning".to_string());
 
-        assert_eq!(report.has_warnings(), true);
+        assert!(report.has_warnings());
 
         Ok(())
     }
@@ -354,7 +1317,7 @@ mod tests {
 
         report.errors.push("Some Error".to_string());
 
-        assert_eq!(report.has_errors(), true);
+        assert!(report.has_errors());
 
         Ok(())
     }
@@ -365,7 +1328,7 @@ mod tests {
 
         report.warnings.push("Some Warning".to_string());
 
-        assert_eq!(report.has_warnings(), true);
+        assert!(report.has_warnings());
 
         Ok(())
     }
@@ -374,7 +1337,7 @@ mod tests {
     fn test_valid_url() -> Result<()> {
         let url = "https://route/to/page";
 
-        assert_eq!(valid_url(&url), true);
+        assert!(valid_url(url));
 
         Ok(())
     }
@@ -383,11 +1346,47 @@ mod tests {
     fn test_invalid_url() -> Result<()> {
         let url = "not_http://route/to/page";
 
-        assert_eq!(valid_url(&url), false);
+        assert!(!valid_url(url));
 
         Ok(())
     }
 
+    #[test]
+    fn method_from_str_is_case_insensitive_for_standard_methods() {
+        assert_eq!("get".parse::<CliMethod>().unwrap(), CliMethod::Get);
+        assert_eq!("Patch".parse::<CliMethod>().unwrap(), CliMethod::Patch);
+        assert_eq!("OPTIONS".parse::<CliMethod>().unwrap(), CliMethod::Options);
+        assert_eq!("head".parse::<CliMethod>().unwrap(), CliMethod::Head);
+    }
+
+    #[test]
+    fn method_from_str_accepts_custom_verbs() {
+        let method = "PURGE".parse::<CliMethod>().unwrap();
+        assert_eq!(method, CliMethod::Custom(Method::from_bytes(b"PURGE").unwrap()));
+        assert_eq!(method.to_method().as_str(), "PURGE");
+        assert!(method.allows_body());
+    }
+
+    #[test]
+    fn method_from_str_rejects_non_tokens() {
+        assert!("BAD VERB".parse::<CliMethod>().is_err());
+        assert!("".parse::<CliMethod>().is_err());
+        assert!("GET/1".parse::<CliMethod>().is_err());
+        assert!("GET\r\nX-Injected: 1".parse::<CliMethod>().is_err());
+    }
+
+    #[test]
+    fn method_body_semantics() {
+        assert!(!CliMethod::Get.allows_body());
+        assert!(!CliMethod::Head.allows_body());
+        assert!(!CliMethod::Delete.allows_body());
+        assert!(!CliMethod::Trace.allows_body());
+        assert!(CliMethod::Post.allows_body());
+        assert!(CliMethod::Put.allows_body());
+        assert!(CliMethod::Patch.allows_body());
+        assert!(CliMethod::Options.allows_body());
+    }
+
     // A minimal struct definition for tests (if not imported)
     // Adjust if your actual struct has more fields.
     fn make_report(warnings: Vec<&str>, errors: Vec<&str>) -> ValidationReport {
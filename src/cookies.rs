@@ -0,0 +1,503 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use reqwest::header::{COOKIE, HeaderMap, HeaderValue, SET_COOKIE};
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next};
+use task_local_extensions::Extensions;
+use url::Url;
+
+use crate::cli::Cli;
+
+// One stored cookie
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    // Lowercase, without a leading dot
+    pub domain: String,
+    // Only sent to exactly `domain`, not its subdomains
+    pub host_only: bool,
+    pub path: String,
+    pub secure: bool,
+    pub http_only: bool,
+    // Seconds since the Unix epoch; None for a session cookie
+    pub expires: Option<u64>,
+}
+
+impl Cookie {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn matches(&self, url: &Url, now: u64) -> bool {
+        let Some(host) = url.host_str() else { return false };
+        let host = host.to_ascii_lowercase();
+        let domain_ok = match self.host_only {
+            true => host == self.domain,
+            false => domain_matches(&host, &self.domain),
+        };
+        domain_ok
+            && path_matches(url.path(), &self.path)
+            && (!self.secure || url.scheme() == "https")
+            && !self.is_expired(now)
+    }
+
+    fn same_slot(&self, other: &Cookie) -> bool {
+        self.name == other.name && self.domain == other.domain && self.path == other.path
+    }
+}
+
+// Cookies kept by domain, path, secure and expiry rules (RFC 6265, without
+// the public suffix list)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CookieStore {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieStore {
+    pub fn cookies(&self) -> &[Cookie] {
+        &self.cookies
+    }
+
+    // Read a Netscape cookie file, as written by curl and browsers' exporters
+    pub fn load(path: &str) -> Result<CookieStore> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Can't read cookie file {}", path))?;
+        CookieStore::parse(&text).with_context(|| format!("Invalid cookie file {}", path))
+    }
+
+    pub fn parse(text: &str) -> Result<CookieStore> {
+        let mut store = CookieStore::default();
+
+        for (n, line) in text.lines().enumerate() {
+            // curl marks HttpOnly cookies with a prefix on an otherwise comment-like line
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                Some(line) => (line, true),
+                None => (line, false),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+                bail!("line {}: expected 7 tab separated fields, got {}", n + 1, fields.len());
+            };
+            let expires: u64 = expires.parse().with_context(|| format!("line {}: invalid expiry {}", n + 1, expires))?;
+
+            store.cookies.push(Cookie {
+                name: name.to_string(),
+                value: value.to_string(),
+                domain: domain.trim_start_matches('.').to_ascii_lowercase(),
+                host_only: !subdomains.eq_ignore_ascii_case("TRUE"),
+                path: path.to_string(),
+                secure: secure.eq_ignore_ascii_case("TRUE"),
+                http_only,
+                // 0 is how session cookies are written
+                expires: (expires != 0).then_some(expires),
+            });
+        }
+
+        let now = unix_now();
+        store.cookies.retain(|cookie| !cookie.is_expired(now));
+        Ok(store)
+    }
+
+    // The store as a Netscape cookie file
+    pub fn to_netscape(&self) -> String {
+        let mut text = String::from("# Netscape HTTP Cookie File\n# Written by rusty_curl. Edit at your own risk.\n\n");
+        let now = unix_now();
+        for cookie in self.cookies.iter().filter(|cookie| !cookie.is_expired(now)) {
+            let domain = match cookie.host_only {
+                true => cookie.domain.clone(),
+                false => format!(".{}", cookie.domain),
+            };
+            text.push_str(&format!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only { "#HttpOnly_" } else { "" },
+                domain,
+                if cookie.host_only { "FALSE" } else { "TRUE" },
+                cookie.path,
+                if cookie.secure { "TRUE" } else { "FALSE" },
+                cookie.expires.unwrap_or(0),
+                cookie.name,
+                cookie.value,
+            ));
+        }
+        text
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, self.to_netscape()).with_context(|| format!("Can't write cookie jar {}", path))
+    }
+
+    // Take in the Set-Cookie headers of a response to `url`
+    pub fn store_response(&mut self, url: &Url, headers: &HeaderMap) {
+        self.store_response_at(url, headers, unix_now());
+    }
+
+    fn store_response_at(&mut self, url: &Url, headers: &HeaderMap, now: u64) {
+        for value in headers.get_all(SET_COOKIE) {
+            let Ok(value) = value.to_str() else { continue };
+            let Some(cookie) = parse_set_cookie(url, value, now) else { continue };
+
+            self.cookies.retain(|existing| !existing.same_slot(&cookie));
+            // An expiry in the past is how a server deletes a cookie
+            if !cookie.is_expired(now) {
+                self.cookies.push(cookie);
+            }
+        }
+    }
+
+    // The Cookie header for a request to `url`, longest paths first
+    pub fn header_for(&self, url: &Url) -> Option<String> {
+        self.header_for_at(url, unix_now())
+    }
+
+    fn header_for_at(&self, url: &Url, now: u64) -> Option<String> {
+        let mut matching: Vec<&Cookie> = self.cookies.iter().filter(|cookie| cookie.matches(url, now)).collect();
+        if matching.is_empty() {
+            return None;
+        }
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        Some(matching.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect::<Vec<_>>().join("; "))
+    }
+}
+
+// A Set-Cookie header, or None when it's malformed or its domain doesn't
+// cover the host that sent it
+fn parse_set_cookie(url: &Url, header: &str, now: u64) -> Option<Cookie> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let mut parts = header.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: value.trim().to_string(),
+        domain: host.clone(),
+        host_only: true,
+        path: default_path(url.path()),
+        secure: false,
+        http_only: false,
+        expires: None,
+    };
+    let mut max_age = None;
+
+    for attribute in parts {
+        let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                // A domain must cover the host, and be more than a bare top level domain
+                if !domain_matches(&host, &domain) || (!domain.contains('.') && domain != host) {
+                    return None;
+                }
+                cookie.domain = domain;
+                cookie.host_only = false;
+            }
+            "path" if value.starts_with('/') => cookie.path = value.to_string(),
+            "expires" => cookie.expires = parse_cookie_date(value).or(cookie.expires),
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            _ => {}
+        }
+    }
+
+    // Max-Age wins over Expires; zero or less means already expired
+    if let Some(max_age) = max_age {
+        cookie.expires = Some(match max_age {
+            ..=0 => 0,
+            seconds => now.saturating_add(seconds as u64),
+        });
+    }
+
+    Some(cookie)
+}
+
+// Expires dates, including the dashed form many servers still send
+fn parse_cookie_date(value: &str) -> Option<u64> {
+    let time = httpdate::parse_http_date(value)
+        .or_else(|_| httpdate::parse_http_date(&value.replacen('-', " ", 2)))
+        .ok()?;
+    Some(time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs())
+}
+
+// The request path's directory, e.g. /account for /account/login
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host.parse::<std::net::IpAddr>().is_err()
+            && host.ends_with(domain)
+            && host.as_bytes().get(host.len() - domain.len() - 1) == Some(&b'.')
+}
+
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || request_path.as_bytes().get(cookie_path.len()) == Some(&b'/'))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()
+}
+
+// What --cookie, --cookie-jar and --session ask for
+#[derive(Debug, Clone, Default)]
+pub struct CookieOptions {
+    // The cookies from --cookie files
+    pub loaded: CookieStore,
+    // Every cookie loaded or received, for --cookie-jar
+    pub jar: Arc<Mutex<CookieStore>>,
+    // name=value pairs from --cookie, sent with every request
+    pub inline: Vec<String>,
+    // Share received cookies with later requests instead of only along
+    // each request's own redirects
+    pub session: bool,
+}
+
+impl CookieOptions {
+    pub fn from_cli(cli: &Cli) -> Result<Option<CookieOptions>> {
+        if cli.cookie.is_empty() && cli.cookie_jar.is_none() && !cli.session {
+            return Ok(None);
+        }
+
+        let mut loaded = CookieStore::default();
+        let mut inline = Vec::new();
+        // Like curl, an argument with a = is cookies, anything else a file
+        for arg in &cli.cookie {
+            if arg.contains('=') {
+                inline.push(arg.trim().trim_end_matches(';').to_string());
+            } else {
+                loaded.cookies.extend(CookieStore::load(arg)?.cookies);
+            }
+        }
+
+        Ok(Some(CookieOptions {
+            jar: Arc::new(Mutex::new(loaded.clone())),
+            loaded,
+            inline,
+            session: cli.session,
+        }))
+    }
+}
+
+// This request's own cookies when they aren't shared: the loaded ones plus
+// whatever its redirects set
+struct RequestCookies(CookieStore);
+
+// The Cookie header given with -H, before any were added to it
+struct UserCookie(Option<String>);
+
+// Sends stored cookies and keeps the ones responses set. It sits inside the
+// redirect middleware so every hop gets its own host's cookies.
+pub struct CookieMiddleware {
+    options: CookieOptions,
+}
+
+impl CookieMiddleware {
+    pub fn new(options: CookieOptions) -> Self {
+        CookieMiddleware { options }
+    }
+}
+
+#[async_trait]
+impl Middleware for CookieMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let url = req.url().clone();
+
+        let stored = match self.options.session {
+            true => self.options.jar.lock().unwrap().header_for(&url),
+            false => {
+                if extensions.get::<RequestCookies>().is_none() {
+                    extensions.insert(RequestCookies(self.options.loaded.clone()));
+                }
+                extensions.get::<RequestCookies>().and_then(|cookies| cookies.0.header_for(&url))
+            }
+        };
+
+        // Cookies given with -H come first. A redirect to another host drops
+        // them along with the header.
+        let header = req.headers().get(COOKIE).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let user_cookie = match extensions.get::<UserCookie>() {
+            Some(UserCookie(user_cookie)) => user_cookie.clone().filter(|_| header.is_some()),
+            None => {
+                extensions.insert(UserCookie(header.clone()));
+                header
+            }
+        };
+        let cookies: Vec<String> = user_cookie
+            .into_iter()
+            .chain(self.options.inline.iter().cloned())
+            .chain(stored)
+            .collect();
+        if !cookies.is_empty() {
+            let mut value = HeaderValue::from_str(&cookies.join("; ")).map_err(anyhow::Error::from)?;
+            value.set_sensitive(true);
+            req.headers_mut().insert(COOKIE, value);
+        }
+
+        let resp = next.run(req, extensions).await?;
+
+        if resp.headers().contains_key(SET_COOKIE) {
+            self.options.jar.lock().unwrap().store_response(resp.url(), resp.headers());
+            if let Some(cookies) = extensions.get_mut::<RequestCookies>() {
+                cookies.0.store_response(resp.url(), resp.headers());
+            }
+        }
+
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+    use reqwest_middleware::ClientBuilder;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn set_cookies(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(SET_COOKIE, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn domain_and_path_rules() {
+        let mut store = CookieStore::default();
+        store.store_response_at(&url("https://www.example.com/account/login"), &set_cookies(&[
+            "host=1",
+            "wide=2; Domain=.example.com; Path=/",
+            "deep=3; Path=/account/settings",
+            "evil=4; Domain=other.com",
+            "tld=5; Domain=com",
+        ]), NOW);
+
+        let header = |u: &str| store.header_for_at(&url(u), NOW);
+        assert_eq!(header("https://www.example.com/account/x").as_deref(), Some("host=1; wide=2"));
+        assert_eq!(header("https://www.example.com/account/settings/a").as_deref(), Some("deep=3; host=1; wide=2"));
+        assert_eq!(header("https://api.example.com/").as_deref(), Some("wide=2"));
+        assert_eq!(header("https://www.example.com/accountant").as_deref(), Some("wide=2"));
+        assert_eq!(header("https://other.com/"), None);
+    }
+
+    #[test]
+    fn secure_and_expiry_rules() {
+        let mut store = CookieStore::default();
+        store.store_response_at(&url("https://example.com/"), &set_cookies(&[
+            "secret=1; Secure; HttpOnly",
+            "short=2; Max-Age=60",
+            "dated=3; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+            "later=4; Expires=Fri, 01-Jan-2100 00:00:00 GMT",
+        ]), NOW);
+
+        assert_eq!(store.header_for_at(&url("https://example.com/"), NOW).as_deref(), Some("secret=1; short=2; later=4"));
+        assert_eq!(store.header_for_at(&url("http://example.com/"), NOW).as_deref(), Some("short=2; later=4"));
+        assert_eq!(store.header_for_at(&url("http://example.com/"), NOW + 61).as_deref(), Some("later=4"));
+
+        // A past expiry deletes the cookie
+        store.store_response_at(&url("https://example.com/"), &set_cookies(&["later=gone; Max-Age=0"]), NOW);
+        assert_eq!(store.header_for_at(&url("http://example.com/"), NOW).as_deref(), Some("short=2"));
+    }
+
+    #[test]
+    fn netscape_round_trip() {
+        let text = "# Netscape HTTP Cookie File\n\
+                    .example.com\tTRUE\t/\tFALSE\t0\tsession\tabc\n\
+                    #HttpOnly_api.example.com\tFALSE\t/v1\tTRUE\t4102444800\ttoken\txyz\n\
+                    old.example.com\tFALSE\t/\tFALSE\t1000\texpired\t1\n";
+        let store = CookieStore::parse(text).unwrap();
+
+        assert_eq!(store.cookies().len(), 2);
+        assert_eq!(store.cookies()[1], Cookie {
+            name: "token".into(),
+            value: "xyz".into(),
+            domain: "api.example.com".into(),
+            host_only: true,
+            path: "/v1".into(),
+            secure: true,
+            http_only: true,
+            expires: Some(4102444800),
+        });
+        assert_eq!(CookieStore::parse(&store.to_netscape()).unwrap(), store);
+
+        let e = CookieStore::parse("example.com\tFALSE\t/\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 1: expected 7 tab separated fields, got 3");
+    }
+
+    fn client(options: CookieOptions) -> reqwest_middleware::ClientWithMiddleware {
+        ClientBuilder::new(reqwest::Client::new()).with(CookieMiddleware::new(options)).build()
+    }
+
+    async fn login_server() -> MockServer {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.path("/login");
+            then.status(200).header("Set-Cookie", "sid=s3cret; Path=/; HttpOnly");
+        }).await;
+        server.mock_async(|when, then| {
+            when.path("/me").header("cookie", "theme=dark; sid=s3cret");
+            then.status(200).body("alice");
+        }).await;
+        server.mock_async(|when, then| {
+            when.path("/me");
+            then.status(401);
+        }).await;
+        server
+    }
+
+    #[tokio::test]
+    async fn session_shares_cookies_with_later_requests() {
+        let server = login_server().await;
+        let options = CookieOptions { inline: vec!["theme=dark".into()], session: true, ..Default::default() };
+        let client = client(options.clone());
+
+        client.get(server.url("/login")).send().await.unwrap();
+        let resp = client.get(server.url("/me")).send().await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "alice");
+
+        let jar = options.jar.lock().unwrap();
+        assert_eq!(jar.cookies()[0].name, "sid");
+        assert!(jar.cookies()[0].http_only);
+    }
+
+    #[tokio::test]
+    async fn without_session_requests_keep_to_themselves() {
+        let server = login_server().await;
+        let options = CookieOptions { inline: vec!["theme=dark".into()], ..Default::default() };
+        let client = client(options.clone());
+
+        client.get(server.url("/login")).send().await.unwrap();
+        let resp = client.get(server.url("/me")).send().await.unwrap();
+        assert_eq!(resp.status(), 401);
+
+        // Still caught for the jar
+        assert_eq!(options.jar.lock().unwrap().cookies().len(), 1);
+    }
+}
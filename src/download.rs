@@ -0,0 +1,584 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use futures::StreamExt;
+use log::info;
+use reqwest::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, HeaderMap, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{Method, Response, StatusCode};
+use reqwest_middleware::ClientWithMiddleware;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use url::Url;
+
+use crate::auth::Auth;
+use crate::checksum::BodyDigest;
+use crate::http::{auth_headers, send};
+use crate::progress::{Direction, Transfer};
+
+// Where a body goes instead of the output
+#[derive(Debug, Clone, PartialEq)]
+pub enum SaveTo {
+    Path(PathBuf),
+    // The last segment of the URL's path, like curl -O
+    RemoteName,
+}
+
+// Stream a response body to a file. It's written to FILE.part and renamed
+// when complete, so FILE only ever exists whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Download {
+    pub to: SaveTo,
+    // Carry on from what's already in FILE.part
+    pub resume: bool,
+    // Fetch this many byte ranges at once when the server takes ranges
+    pub segments: u32,
+}
+
+// What was saved, for the output
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedFile {
+    pub path: String,
+    // Bytes written this time
+    pub bytes: u64,
+    // Size of the finished file
+    pub size: u64,
+    // Where this download picked up, 0 for a fresh one
+    pub resumed_from: u64,
+    // Ranges fetched at once, 1 for a single stream
+    pub segments: u32,
+}
+
+// A download about to start
+#[derive(Debug)]
+pub struct PreparedDownload {
+    path: PathBuf,
+    offset: u64,
+    if_range: Option<String>,
+    segments: u32,
+}
+
+impl Download {
+    // Work out the file and, when resuming, how much of it there is already
+    pub async fn prepare(&self, url: &str) -> Result<PreparedDownload> {
+        let path = match &self.to {
+            SaveTo::Path(path) => path.clone(),
+            SaveTo::RemoteName => remote_name(url)?,
+        };
+
+        let (offset, if_range) = match self.resume {
+            true => match tokio::fs::metadata(part_path(&path)).await {
+                Ok(meta) if meta.len() > 0 => (meta.len(), tokio::fs::read_to_string(if_range_path(&path)).await.ok()),
+                _ => (0, None),
+            },
+            false => (0, None),
+        };
+
+        Ok(PreparedDownload { path, offset, if_range, segments: self.segments })
+    }
+}
+
+impl PreparedDownload {
+    // Range (and If-Range, so a changed file is sent whole) for a resume
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if self.offset > 0 {
+            info!("Download: resuming {} at {}", self.path.display(), self.offset);
+            headers.push((RANGE.to_string(), format!("bytes={}-", self.offset)));
+            if let Some(validator) = &self.if_range {
+                headers.push((IF_RANGE.to_string(), validator.trim().to_string()));
+            }
+        }
+        headers
+    }
+
+    // Whether the response holds the file (or says it's already all there)
+    pub fn wants(&self, status: StatusCode) -> bool {
+        status.is_success() || status == StatusCode::RANGE_NOT_SATISFIABLE && self.offset > 0
+    }
+
+    // Stream the body to FILE.part, then rename it to FILE
+    pub async fn save(self, resp: Response, digest: &mut BodyDigest, transfer: &Transfer) -> Result<SavedFile> {
+        let part = part_path(&self.path);
+        let status = resp.status();
+
+        let start = match status {
+            StatusCode::PARTIAL_CONTENT => {
+                let start = content_range(resp.headers()).map(|(start, _)| start);
+                if start != Some(self.offset) {
+                    bail!("Can't resume {}: the server sent a range starting at {:?}, not {}", self.path.display(), start, self.offset);
+                }
+                digest.update_from_file(&part).await?;
+                self.offset
+            }
+            // Nothing left to send: the part file is the whole thing
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                let total = content_range(resp.headers()).map(|(_, total)| total);
+                if total != Some(self.offset) {
+                    bail!("Can't resume {}: the server says the file is {:?} bytes, not {}", self.path.display(), total, self.offset);
+                }
+                digest.update_from_file(&part).await?;
+                return self.finish(&part, 0).await;
+            }
+            // Range ignored, or the file changed since (If-Range)
+            _ => 0,
+        };
+
+        transfer.begin(Direction::Download, start, resp.content_length().map(|len| start + len));
+
+        // Remember the version being saved so a later resume can check it
+        match validator(resp.headers()) {
+            Some(validator) if start == 0 => tokio::fs::write(if_range_path(&self.path), validator).await?,
+            _ => {}
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(start > 0)
+            .truncate(start == 0)
+            .open(&part).await
+            .with_context(|| format!("Can't write {}", part.display()))?;
+
+        let mut bytes = 0;
+        let mut stream = resp.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            file.write_all(&chunk).await?;
+            digest.update(&chunk);
+            transfer.advance(chunk.len());
+            bytes += chunk.len() as u64;
+        }
+        file.sync_all().await?;
+
+        PreparedDownload { offset: start, ..self }.finish(&part, bytes).await
+    }
+
+    // Fetch the file as ranges at once, after a HEAD to check the server
+    // takes them and to get the size. None when it doesn't (or a range comes
+    // back whole), so the caller falls back to a single GET. A resume stays
+    // a single stream.
+    pub async fn fetch_segments(
+        &self,
+        client: &ClientWithMiddleware,
+        url: &str,
+        headers: &[(String, String)],
+        auth: Option<&Auth>,
+        transfer: &Transfer,
+    ) -> Result<Option<(Response, SavedFile)>> {
+        if self.segments < 2 || self.offset > 0 {
+            return Ok(None);
+        }
+        if let Some(Auth::Digest { .. }) = auth {
+            info!("Download: not splitting {}: Digest auth answers one challenge per request", url);
+            return Ok(None);
+        }
+
+        let head_auth = auth_headers(auth, &Method::HEAD, url, headers, None).await?;
+        let head = send(client, url, &Method::HEAD, None, headers, head_auth, transfer).await?;
+        let takes_ranges = head.headers().get(ACCEPT_RANGES).is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"bytes"));
+        // The header, since a HEAD response's body is always empty
+        let size = head.headers().get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        let size = match size {
+            Some(size) if head.status().is_success() && takes_ranges && size >= self.segments as u64 => size,
+            _ => {
+                info!("Download: not splitting {}: {} without byte ranges and a size", url, head.status());
+                return Ok(None);
+            }
+        };
+
+        // Not FILE.part: it's full length with holes until every range is in,
+        // so a resume mustn't take it for what's been fetched so far
+        let part = segments_path(&self.path);
+        let file = tokio::fs::File::create(&part).await
+            .with_context(|| format!("Can't write {}", part.display()))?;
+        file.set_len(size).await?;
+
+        info!("Download: {} bytes of {} in {} segments", size, url, self.segments);
+        transfer.begin(Direction::Download, 0, Some(size));
+        let segment = Segment {
+            client,
+            url,
+            headers,
+            auth,
+            // Any that changes part way comes back whole, and is fetched again
+            if_range: validator(head.headers()),
+            part: &part,
+            size,
+            transfer,
+        };
+        let fetched = futures::future::try_join_all(split(size, self.segments).into_iter()
+            .map(|(start, end)| segment.fetch(start, end)))
+            .await;
+
+        let fetched = match fetched {
+            Ok(fetched) if fetched.iter().all(Option::is_some) => fetched,
+            Ok(_) => {
+                info!("Download: {} sent a range whole, fetching it in one", url);
+                let _ = tokio::fs::remove_file(&part).await;
+                return Ok(None);
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&part).await;
+                return Err(e);
+            }
+        };
+
+        let bytes: u64 = fetched.into_iter().flatten().sum();
+        if bytes != size {
+            let _ = tokio::fs::remove_file(&part).await;
+            bail!("Download of {} came to {} bytes, not {}", url, bytes, size);
+        }
+
+        let saved = self.finish(&part, bytes).await?;
+        Ok(Some((head, SavedFile { segments: self.segments, ..saved })))
+    }
+
+    async fn finish(&self, part: &Path, bytes: u64) -> Result<SavedFile> {
+        tokio::fs::rename(part, &self.path).await
+            .with_context(|| format!("Can't move {} to {}", part.display(), self.path.display()))?;
+        let _ = tokio::fs::remove_file(if_range_path(&self.path)).await;
+
+        Ok(SavedFile {
+            path: self.path.display().to_string(),
+            bytes,
+            size: self.offset + bytes,
+            resumed_from: self.offset,
+            segments: 1,
+        })
+    }
+}
+
+// One range request of a segmented download, written at its place in FILE.part
+struct Segment<'a> {
+    client: &'a ClientWithMiddleware,
+    url: &'a str,
+    headers: &'a [(String, String)],
+    auth: Option<&'a Auth>,
+    if_range: Option<String>,
+    part: &'a Path,
+    size: u64,
+    transfer: &'a Transfer,
+}
+
+impl Segment<'_> {
+    // The bytes written, or None when the server sent something other than
+    // the range
+    async fn fetch(&self, start: u64, end: u64) -> Result<Option<u64>> {
+        let mut headers = self.headers.to_vec();
+        headers.push((RANGE.to_string(), format!("bytes={}-{}", start, end)));
+        if let Some(validator) = &self.if_range {
+            headers.push((IF_RANGE.to_string(), validator.clone()));
+        }
+
+        let auth = auth_headers(self.auth, &Method::GET, self.url, &headers, None).await?;
+        let resp = send(self.client, self.url, &Method::GET, None, &headers, auth, self.transfer).await?;
+        if resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Ok(None);
+        }
+        let range = content_range(resp.headers());
+        if range != Some((start, self.size)) {
+            bail!("Asked {} for bytes {}-{} of {}, got {:?}", self.url, start, end, self.size, resp.headers().get(CONTENT_RANGE));
+        }
+
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(self.part).await
+            .with_context(|| format!("Can't write {}", self.part.display()))?;
+        file.seek(SeekFrom::Start(start)).await?;
+
+        let len = end - start + 1;
+        let mut bytes = 0;
+        let mut stream = resp.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            bytes += chunk.len() as u64;
+            if bytes > len {
+                bail!("Bytes {}-{} of {} ran past the end of the range", start, end, self.url);
+            }
+            file.write_all(&chunk).await?;
+            self.transfer.advance(chunk.len());
+        }
+        file.sync_all().await?;
+
+        if bytes != len {
+            bail!("Bytes {}-{} of {} stopped after {} bytes", start, end, self.url, bytes);
+        }
+        Ok(Some(bytes))
+    }
+}
+
+// `count` ranges (first and last byte) covering `size` bytes, the first
+// ones a byte longer when it doesn't divide evenly
+fn split(size: u64, count: u32) -> Vec<(u64, u64)> {
+    let count = count as u64;
+    let mut start = 0;
+    (0..count)
+        .map(|i| {
+            let len = size / count + u64::from(i < size % count);
+            let range = (start, start + len - 1);
+            start += len;
+            range
+        })
+        .collect()
+}
+
+// The file name -O uses
+pub fn remote_name(url: &str) -> Result<PathBuf> {
+    let parsed = Url::parse(url)?;
+    match parsed.path_segments().and_then(|mut segments| segments.next_back()) {
+        Some(name) if !name.is_empty() && name != "." && name != ".." => Ok(PathBuf::from(name)),
+        _ => bail!("Can't get a file name from {}: use --save", url),
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+// Where a segmented download's ranges are written
+fn segments_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".segments");
+    PathBuf::from(part)
+}
+
+// Holds the ETag or Last-Modified of the part file's download
+fn if_range_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part.if-range");
+    PathBuf::from(part)
+}
+
+// A strong ETag, or else Last-Modified. Weak ETags can't be used with If-Range.
+fn validator(headers: &HeaderMap) -> Option<String> {
+    let etag = headers.get(ETAG).and_then(|v| v.to_str().ok()).filter(|etag| !etag.starts_with("W/"));
+    etag.or_else(|| headers.get(LAST_MODIFIED).and_then(|v| v.to_str().ok())).map(str::to_string)
+}
+
+// The start and total size from `Content-Range: bytes 100-199/1000` or
+// `bytes */1000`
+fn content_range(headers: &HeaderMap) -> Option<(u64, u64)> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?.strip_prefix("bytes ")?;
+    let (range, total) = value.split_once('/')?;
+    let total = total.trim().parse().ok()?;
+    let start = match range.trim() {
+        "*" => total,
+        range => range.split_once('-')?.0.parse().ok()?,
+    };
+    Some((start, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+
+    async fn get(url: &str, download: &Download) -> Result<SavedFile> {
+        let prepared = download.prepare(url).await?;
+        let mut req = reqwest::Client::new().get(url);
+        for (k, v) in prepared.headers() {
+            req = req.header(k, v);
+        }
+        prepared.save(req.send().await?, &mut BodyDigest::default(), &Transfer::hidden()).await
+    }
+
+    #[tokio::test]
+    async fn saves_through_a_part_file() {
+        let server = MockServer::start_async().await;
+        let body: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        server.mock_async(|when, then| {
+            when.method(GET).path("/files/artifact.bin");
+            then.status(200).header("ETag", "\"v1\"").body(body.clone());
+        }).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("artifact.bin");
+        let download = Download { to: SaveTo::Path(path.clone()), resume: false, segments: 1 };
+
+        let saved = get(&server.url("/files/artifact.bin"), &download).await.unwrap();
+
+        assert_eq!(saved.bytes, 100_000);
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        assert!(!part_path(&path).exists());
+        assert!(!if_range_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn resumes_with_range_and_if_range() {
+        let server = MockServer::start_async().await;
+        let resumed = server.mock_async(|when, then| {
+            when.method(GET).path("/big.iso").header("range", "bytes=5-").header("if-range", "\"v1\"");
+            then.status(206).header("Content-Range", "bytes 5-9/10").body("56789");
+        }).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.iso");
+        std::fs::write(part_path(&path), "01234").unwrap();
+        std::fs::write(if_range_path(&path), "\"v1\"").unwrap();
+        let download = Download { to: SaveTo::Path(path.clone()), resume: true, segments: 1 };
+
+        let saved = get(&server.url("/big.iso"), &download).await.unwrap();
+
+        resumed.assert_async().await;
+        assert_eq!(saved, SavedFile { path: path.display().to_string(), bytes: 5, size: 10, resumed_from: 5, segments: 1 });
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0123456789");
+    }
+
+    #[tokio::test]
+    async fn changed_file_starts_over() {
+        let server = MockServer::start_async().await;
+        // If-Range didn't match, so the whole new version comes back
+        server.mock_async(|when, then| {
+            when.method(GET).path("/big.iso").header("range", "bytes=5-");
+            then.status(200).header("ETag", "\"v2\"").body("abcdefghij");
+        }).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.iso");
+        std::fs::write(part_path(&path), "01234").unwrap();
+        let download = Download { to: SaveTo::Path(path.clone()), resume: true, segments: 1 };
+
+        let saved = get(&server.url("/big.iso"), &download).await.unwrap();
+
+        assert_eq!(saved.resumed_from, 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "abcdefghij");
+    }
+
+    #[tokio::test]
+    async fn complete_part_file_is_finished() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.method(GET).path("/big.iso").header("range", "bytes=10-");
+            then.status(416).header("Content-Range", "bytes */10");
+        }).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.iso");
+        std::fs::write(part_path(&path), "0123456789").unwrap();
+        let download = Download { to: SaveTo::Path(path.clone()), resume: true, segments: 1 };
+
+        let saved = get(&server.url("/big.iso"), &download).await.unwrap();
+
+        assert_eq!((saved.bytes, saved.size), (0, 10));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0123456789");
+    }
+
+    async fn get_segmented(url: &str, download: &Download) -> Result<Option<(Response, SavedFile)>> {
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        download.prepare(url).await?.fetch_segments(&client, url, &[], None, &Transfer::hidden()).await
+    }
+
+    #[tokio::test]
+    async fn fetches_segments_into_place() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.method(httpmock::Method::HEAD).path("/artifact.bin");
+            then.status(200).header("Accept-Ranges", "bytes").header("Content-Length", "10").header("ETag", "\"v1\"");
+        }).await;
+        let mut ranges = Vec::new();
+        for (range, body) in [("0-3", "0123"), ("4-6", "456"), ("7-9", "789")] {
+            ranges.push(server.mock_async(|when, then| {
+                when.method(GET).path("/artifact.bin").header("range", format!("bytes={}", range)).header("if-range", "\"v1\"");
+                then.status(206).header("Content-Range", format!("bytes {}/10", range)).body(body);
+            }).await);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("artifact.bin");
+        let download = Download { to: SaveTo::Path(path.clone()), resume: false, segments: 3 };
+
+        let (head, saved) = get_segmented(&server.url("/artifact.bin"), &download).await.unwrap().unwrap();
+
+        for range in ranges {
+            range.assert_async().await;
+        }
+        assert_eq!(head.status(), 200);
+        assert_eq!((saved.bytes, saved.size, saved.segments), (10, 10, 3));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0123456789");
+        assert!(!segments_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn resume_after_interrupted_segments_starts_over() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.method(httpmock::Method::HEAD).path("/artifact.bin");
+            then.status(200).header("Accept-Ranges", "bytes").header("Content-Length", "10");
+        }).await;
+        server.mock_async(|when, then| {
+            when.method(GET).path("/artifact.bin").header("range", "bytes=0-4");
+            then.status(206).header("Content-Range", "bytes 0-4/10").body("01234");
+        }).await;
+        server.mock_async(|when, then| {
+            when.method(GET).path("/artifact.bin").header("range", "bytes=5-9");
+            then.status(206).header("Content-Range", "bytes 5-9/10").body("56789").delay(std::time::Duration::from_secs(30));
+        }).await;
+        let whole = server.mock_async(|when, then| {
+            when.method(GET).path("/artifact.bin").matches(|req| !req.headers.iter().flatten().any(|(k, _)| k == "range"));
+            then.status(200).body("0123456789");
+        }).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("artifact.bin");
+        let url = server.url("/artifact.bin");
+
+        // Killed while the second range is still coming
+        let segmented = Download { to: SaveTo::Path(path.clone()), resume: false, segments: 2 };
+        let task = tokio::spawn({
+            let url = url.clone();
+            async move { get_segmented(&url, &segmented).await.map(|_| ()) }
+        });
+        while std::fs::read(segments_path(&path)).map_or(true, |data| !data.starts_with(b"01234")) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        task.abort();
+        assert!(!part_path(&path).exists());
+
+        let resumed = Download { to: SaveTo::Path(path.clone()), resume: true, segments: 1 };
+        let saved = get(&url, &resumed).await.unwrap();
+
+        whole.assert_async().await;
+        assert_eq!(saved.resumed_from, 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0123456789");
+    }
+
+    #[tokio::test]
+    async fn falls_back_without_ranges() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.method(httpmock::Method::HEAD).path("/no-ranges");
+            then.status(200).header("Content-Length", "10");
+        }).await;
+        server.mock_async(|when, then| {
+            when.method(httpmock::Method::HEAD).path("/ignores-ranges");
+            then.status(200).header("Accept-Ranges", "bytes").header("Content-Length", "10");
+        }).await;
+        server.mock_async(|when, then| {
+            when.method(GET).path("/ignores-ranges");
+            then.status(200).body("0123456789");
+        }).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let download = Download { to: SaveTo::Path(path.clone()), resume: false, segments: 2 };
+
+        assert!(get_segmented(&server.url("/no-ranges"), &download).await.unwrap().is_none());
+        assert!(get_segmented(&server.url("/ignores-ranges"), &download).await.unwrap().is_none());
+        assert!(!segments_path(&path).exists());
+    }
+
+    #[test]
+    fn splits_evenly_then_the_rest() {
+        assert_eq!(split(10, 3), vec![(0, 3), (4, 6), (7, 9)]);
+        assert_eq!(split(4, 4), vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+    }
+
+    #[test]
+    fn remote_names() {
+        assert_eq!(remote_name("https://example.com/releases/tool-1.2.tar.gz?sig=abc").unwrap(), PathBuf::from("tool-1.2.tar.gz"));
+        assert!(remote_name("https://example.com/").is_err());
+        assert!(remote_name("https://example.com/dir/").is_err());
+    }
+}
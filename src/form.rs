@@ -0,0 +1,376 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use url::form_urlencoded;
+
+use crate::body::RequestBody;
+
+// One field of a multipart form, parsed from curl style -F arguments:
+//   name=value                       a text field
+//   name=<path                       a text field read from a file
+//   name=@path;type=...;filename=... a file upload
+#[derive(Debug, PartialEq)]
+struct FormPart {
+    name: String,
+    data: Vec<u8>,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+// Build a multipart/form-data body from -F fields. Returns the body and the
+// Content-Type header, which carries the boundary. Files are read into
+// memory, unlike big -b @file bodies, since the boundary is checked against
+// every part.
+pub fn multipart(fields: &[String]) -> Result<(RequestBody, String)> {
+    let parts = fields.iter()
+        .map(|field| parse_part(field))
+        .collect::<Result<Vec<_>>>()?;
+
+    let boundary = make_boundary(&parts);
+    let body = encode_multipart(&parts, &boundary);
+
+    Ok((RequestBody::Bytes(body.into()), format!("multipart/form-data; boundary={}", boundary)))
+}
+
+// Build an application/x-www-form-urlencoded body from --data-urlencode
+// fields, joined with `&` the way curl does.
+pub fn urlencoded(fields: &[String]) -> Result<RequestBody> {
+    let encoded = fields.iter()
+        .map(|field| encode_field(field))
+        .collect::<Result<Vec<_>>>()?
+        .join("&");
+
+    Ok(RequestBody::Bytes(Bytes::from(encoded)))
+}
+
+// Check a -F field without reading any files
+pub fn validate_part(field: &str) -> Result<(), String> {
+    let (name, value) = field.split_once('=')
+        .ok_or_else(|| format!("Invalid form field `{}`: expected name=value", field))?;
+
+    if name.is_empty() {
+        return Err(format!("Invalid form field `{}`: empty name", field));
+    }
+
+    if let Some(spec) = value.strip_prefix('@') {
+        let mut attrs = spec.split(';');
+        let path = attrs.next().unwrap_or_default();
+        if !Path::new(path).is_file() {
+            return Err(format!("Form file {} not found", path));
+        }
+        for attr in attrs {
+            if let Some(("type", v)) = attr.split_once('=') {
+                check_content_type(field, v)?;
+            }
+        }
+    } else if let Some(path) = value.strip_prefix('<')
+        && !Path::new(path).is_file() {
+        return Err(format!("Form file {} not found", path));
+    }
+
+    Ok(())
+}
+
+// Check a --data-urlencode field without reading any files
+pub fn validate_urlencoded(field: &str) -> Result<(), String> {
+    if let (_, UrlencodedSource::File(path)) = split_urlencoded(field)
+        && !Path::new(path).is_file() {
+        return Err(format!("Form file {} not found", path));
+    }
+
+    Ok(())
+}
+
+fn parse_part(field: &str) -> Result<FormPart> {
+    let (name, value) = field.split_once('=')
+        .with_context(|| format!("Invalid form field `{}`: expected name=value", field))?;
+
+    if let Some(spec) = value.strip_prefix('@') {
+        // @path followed by optional ;type= and ;filename= attributes
+        let mut attrs = spec.split(';');
+        let path = attrs.next().unwrap_or_default();
+        let mut content_type = None;
+        let mut filename = None;
+
+        for attr in attrs {
+            match attr.split_once('=') {
+                Some(("type", v)) => {
+                    check_content_type(field, v).map_err(anyhow::Error::msg)?;
+                    content_type = Some(v.to_string());
+                }
+                Some(("filename", v)) => filename = Some(v.to_string()),
+                _ => bail!("Invalid form field `{}`: unknown attribute `{}`", field, attr),
+            }
+        }
+
+        let data = fs::read(path).with_context(|| format!("Failed to read form file {}", path))?;
+        let filename = filename.unwrap_or_else(|| {
+            Path::new(path).file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default()
+        });
+        let content_type = content_type.unwrap_or_else(|| guess_content_type(&filename).to_string());
+
+        Ok(FormPart { name: name.to_string(), data, filename: Some(filename), content_type: Some(content_type) })
+    } else if let Some(path) = value.strip_prefix('<') {
+        let data = fs::read(path).with_context(|| format!("Failed to read form file {}", path))?;
+        Ok(FormPart { name: name.to_string(), data, filename: None, content_type: None })
+    } else {
+        Ok(FormPart { name: name.to_string(), data: value.as_bytes().to_vec(), filename: None, content_type: None })
+    }
+}
+
+// The ;type= value goes into the part's headers as is, so a CR LF in it
+// would start another header
+fn check_content_type(field: &str, content_type: &str) -> Result<(), String> {
+    if content_type.chars().any(|c| c.is_control()) {
+        return Err(format!("Invalid form field `{}`: control characters in type", field.escape_debug()));
+    }
+    Ok(())
+}
+
+fn encode_multipart(parts: &[FormPart], boundary: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    for part in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(format!("Content-Disposition: form-data; name=\"{}\"", escape_quoted(&part.name)).as_bytes());
+        if let Some(filename) = &part.filename {
+            body.extend_from_slice(format!("; filename=\"{}\"", escape_quoted(filename)).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        if let Some(content_type) = &part.content_type {
+            body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        body.extend_from_slice(&part.data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    body
+}
+
+// A boundary that doesn't occur in any of the parts
+fn make_boundary(parts: &[FormPart]) -> String {
+    let mut seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default()
+        ^ u128::from(std::process::id());
+
+    loop {
+        let boundary = format!("------------------------{:016x}", seed as u64);
+        if !parts.iter().any(|p| contains(&p.data, boundary.as_bytes())) {
+            return boundary;
+        }
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+// Quotes, CR and LF can't appear in a quoted header parameter, so percent
+// encode them like browsers do
+fn escape_quoted(s: &str) -> String {
+    s.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+fn guess_content_type(filename: &str) -> &'static str {
+    let ext = Path::new(filename)
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+// Split a --data-urlencode field into its name and where the content comes
+// from. Like curl, the first `=` or `@` decides: `name=content`, `name@file`,
+// or bare content.
+fn split_urlencoded(field: &str) -> (&str, UrlencodedSource<'_>) {
+    match field.find(['=', '@']) {
+        Some(pos) if field.as_bytes()[pos] == b'@' => (&field[..pos], UrlencodedSource::File(&field[pos + 1..])),
+        Some(pos) => (&field[..pos], UrlencodedSource::Content(&field[pos + 1..])),
+        None => ("", UrlencodedSource::Content(field)),
+    }
+}
+
+enum UrlencodedSource<'a> {
+    Content(&'a str),
+    File(&'a str),
+}
+
+fn encode_field(field: &str) -> Result<String> {
+    let (name, source) = split_urlencoded(field);
+    let content = match source {
+        UrlencodedSource::Content(content) => content.as_bytes().to_vec(),
+        UrlencodedSource::File(path) => fs::read(path).with_context(|| format!("Failed to read form file {}", path))?,
+    };
+
+    let encoded: String = form_urlencoded::byte_serialize(&content).collect();
+    if name.is_empty() {
+        Ok(encoded)
+    } else {
+        Ok(format!("{}={}", name, encoded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn body_bytes(body: RequestBody) -> Vec<u8> {
+        match body {
+            RequestBody::Bytes(data) => data.to_vec(),
+            _ => panic!("Expected an in-memory body"),
+        }
+    }
+
+    #[test]
+    fn parse_part_text_field() -> Result<()> {
+        let part = parse_part("name=value with spaces")?;
+
+        assert_eq!(part, FormPart {
+            name: "name".to_string(),
+            data: b"value with spaces".to_vec(),
+            filename: None,
+            content_type: None,
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_part_file_field_with_type() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&[0x89, b'P', b'N', b'G'])?;
+        let path = file.path().display().to_string();
+
+        let part = parse_part(&format!("file=@{};type=image/x-custom", path))?;
+
+        assert_eq!(part.name, "file");
+        assert_eq!(part.data, vec![0x89, b'P', b'N', b'G']);
+        assert_eq!(part.content_type.as_deref(), Some("image/x-custom"));
+        assert_eq!(part.filename, file.path().file_name().map(|f| f.to_string_lossy().into_owned()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_part_file_field_guesses_type_from_filename() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        let path = file.path().display().to_string();
+
+        let part = parse_part(&format!("file=@{};filename=photo.PNG", path))?;
+
+        assert_eq!(part.filename.as_deref(), Some("photo.PNG"));
+        assert_eq!(part.content_type.as_deref(), Some("image/png"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_part_rejects_missing_equals() {
+        assert!(parse_part("novalue").is_err());
+    }
+
+    #[test]
+    fn parse_part_rejects_control_characters_in_type() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        let field = format!("f=@{};type=text/plain\r\nX-Injected: 1", file.path().display());
+
+        assert!(parse_part(&field).unwrap_err().to_string().contains("control characters in type"));
+        assert!(validate_part(&field).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn multipart_body_layout() -> Result<()> {
+        let (body, content_type) = multipart(&["a=1".to_string(), "b=two".to_string()])?;
+
+        let boundary = content_type.strip_prefix("multipart/form-data; boundary=").unwrap().to_string();
+        let body = String::from_utf8(body_bytes(body)).unwrap();
+
+        assert_eq!(body, format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"b\"\r\n\r\ntwo\r\n\
+             --{b}--\r\n",
+            b = boundary
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn multipart_escapes_quotes_in_names() {
+        assert_eq!(escape_quoted("a\"b\r\nc"), "a%22b%0D%0Ac");
+    }
+
+    #[test]
+    fn urlencoded_fields() -> Result<()> {
+        let body = urlencoded(&[
+            "name=John Doe & co".to_string(),
+            "=just content".to_string(),
+            "bare=?".to_string(),
+            "plain text".to_string(),
+        ])?;
+
+        assert_eq!(
+            String::from_utf8(body_bytes(body)).unwrap(),
+            "name=John+Doe+%26+co&just+content&bare=%3F&plain+text"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn urlencoded_from_file() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all("a/b ü".as_bytes())?;
+        let path = file.path().display().to_string();
+
+        let body = urlencoded(&[format!("data@{}", path), format!("@{}", path)])?;
+
+        assert_eq!(
+            String::from_utf8(body_bytes(body)).unwrap(),
+            "data=a%2Fb+%C3%BC&a%2Fb+%C3%BC"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_part_checks_files() {
+        assert!(validate_part("a=b").is_ok());
+        assert!(validate_part("ab").is_err());
+        assert!(validate_part("=b").is_err());
+        assert!(validate_part("f=@/no/such/form/file;type=text/plain").is_err());
+        assert!(validate_part("f=</no/such/form/file").is_err());
+    }
+
+    #[test]
+    fn validate_urlencoded_checks_files() {
+        assert!(validate_urlencoded("a=b@c").is_ok());
+        assert!(validate_urlencoded("a@/no/such/form/file").is_err());
+    }
+}
@@ -1,102 +1,551 @@
+use std::borrow::Cow;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use encoding_rs::{Encoding, UTF_8};
+use futures::stream::{BoxStream, Stream, StreamExt};
 use log::{info};
-use reqwest::{Client, Method};
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
+use reqwest::{Client, Method, Request, Response, StatusCode};
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderValue};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware, Next};
+use reqwest_retry::{
+    RetryTransientMiddleware, Retryable, RetryableStrategy, default_on_request_failure,
+    default_on_request_success, policies::ExponentialBackoff,
+};
+use task_local_extensions::Extensions;
 use tokio::time::Instant;
 
+use crate::auth::{Auth, DigestChallenge};
+use crate::body::RequestBody;
+use crate::checksum::{BodyDigest, DigestOptions};
 use crate::cli::{CliMethod};
+use crate::cookies::{CookieMiddleware, CookieOptions};
+use crate::download::{Download, SavedFile};
+use crate::limit::{HostLimits, RateLimiter};
+use crate::oauth2::{OAuth2Middleware, OAuth2Options};
+use crate::progress::{Direction, Progress, Transfer};
+use crate::proxy::ProxyOptions;
+use crate::redirect::{Redirect, RedirectChain, RedirectMiddleware, RedirectOptions};
+use crate::tls::{TlsOptions, client_config};
+use crate::verbose::VerboseMiddleware;
 
 const REQUEST_TIMEOUT: u64 = 10;
+const MAX_RETRIES: u32 = 3;
+const MAX_PARALLEL: usize = 50;
+
+// How the client times out and retries
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    // Limit on each whole request, from connecting to reading the body
+    pub timeout: Duration,
+    pub connect_timeout: Option<Duration>,
+    // Zero turns retries off
+    pub retries: u32,
+    // Cap on the backoff between retries
+    pub retry_max_delay: Option<Duration>,
+    // Retry on exactly these statuses instead of the default 5xx, 408 and 429
+    pub retry_on: Vec<u16>,
+    // Attach an OAuth2 client-credentials token to every request
+    pub oauth2: Option<OAuth2Options>,
+    // Certificates, pinning and TLS versions
+    pub tls: TlsOptions,
+    // Proxies to go through instead of the environment's
+    pub proxy: ProxyOptions,
+    pub redirects: RedirectOptions,
+    // Send and keep cookies
+    pub cookies: Option<CookieOptions>,
+    // Trace connections and headers to stderr
+    pub verbose: bool,
+    // With verbose, a separate handshake per https host for the TLS details
+    pub tls_probe: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            timeout: Duration::from_secs(REQUEST_TIMEOUT),
+            connect_timeout: None,
+            retries: MAX_RETRIES,
+            retry_max_delay: None,
+            retry_on: Vec::new(),
+            oauth2: None,
+            tls: TlsOptions::default(),
+            proxy: ProxyOptions::default(),
+            redirects: RedirectOptions::default(),
+            cookies: None,
+            verbose: false,
+            tls_probe: false,
+        }
+    }
+}
 
 pub struct HttpResult {
+    pub method: Method,
+    // The URL the response came from
+    pub url: String,
     pub status: reqwest::StatusCode,
     pub headers: reqwest::header::HeaderMap,
     pub content_length: Option<u64>,
-    pub body: String,
+    // The body exactly as received. Use text() to read it as text.
+    pub body: Bytes,
+    // Time until the response headers arrived
+    pub time_to_first_byte: Duration,
     pub latency: Duration,
+    // The redirects followed on the way, in order
+    pub redirects: Vec<Redirect>,
+    // Where the body went instead of `body`, for downloads
+    pub saved: Option<SavedFile>,
+    // The body's digest as ALGO:HEX, with --print-digest
+    pub digest: Option<String>,
+}
+
+impl HttpResult {
+    // The body decoded with the Content-Type's charset (or a BOM), UTF-8 when
+    // neither says. Anything that doesn't decode becomes U+FFFD.
+    pub fn text(&self) -> Cow<'_, str> {
+        let encoding = charset(&self.headers).unwrap_or(UTF_8);
+        encoding.decode(&self.body).0
+    }
+
+    // Whether the body isn't text: a Content-Type that isn't a text type and
+    // a body with NUL bytes or that isn't UTF-8, like curl's check
+    pub fn is_binary(&self) -> bool {
+        match media_type(&self.headers) {
+            Some(mime) if is_text_type(&mime) => false,
+            _ => self.body.contains(&0) || std::str::from_utf8(&self.body).is_err(),
+        }
+    }
+}
+
+// The Content-Type's media type, lowercase and without parameters
+pub(crate) fn media_type(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    Some(value.split(';').next()?.trim().to_ascii_lowercase())
+}
+
+fn charset(headers: &HeaderMap) -> Option<&'static Encoding> {
+    let value = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    value.split(';').skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| Encoding::for_label(label.trim().trim_matches('"').as_bytes()))
+}
+
+fn is_text_type(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || ["application/json", "application/xml", "application/javascript", "application/x-www-form-urlencoded"].contains(&mime)
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+}
+
+// How many requests go out at once, and how fast
+#[derive(Debug, Clone)]
+pub struct ConcurrencyOptions {
+    // Requests in flight at once
+    pub parallel: usize,
+    // Requests started per second, across all hosts
+    pub rate: Option<f64>,
+    // Requests in flight at once to any one host
+    pub per_host: Option<usize>,
+    // Yield results in input order rather than as they complete
+    pub ordered: bool,
+    // Lines on stderr for the requests in flight
+    pub progress: Progress,
+}
+
+impl Default for ConcurrencyOptions {
+    fn default() -> Self {
+        ConcurrencyOptions {
+            parallel: MAX_PARALLEL,
+            rate: None,
+            per_host: None,
+            ordered: true,
+            progress: Progress::default(),
+        }
+    }
 }
 
-pub fn make_client() -> ClientWithMiddleware {
+pub fn make_client(options: &ClientOptions) -> Result<ClientWithMiddleware> {
     info!("make_client: Creating Client");
 
-    let base_client = Client::builder()
-        .timeout(Duration::from_secs(REQUEST_TIMEOUT))
-        .build();
+    // Redirects are followed by RedirectMiddleware, if at all
+    // Keep the server's certificate on responses for the verbose trace
+    let mut base_client = Client::builder()
+        .timeout(options.timeout)
+        .redirect(reqwest::redirect::Policy::none())
+        .tls_info(options.verbose);
+    if let Some(connect_timeout) = options.connect_timeout {
+        base_client = base_client.connect_timeout(connect_timeout);
+    }
+    if !options.tls.is_default() {
+        base_client = base_client.use_preconfigured_tls(client_config(&options.tls)?);
+    }
+    let proxy_routes = options.proxy.routes()?;
+    base_client = options.proxy.apply(base_client, &proxy_routes)?;
+
+    let base_client = base_client.build()?;
+    let mut client = ClientBuilder::new(base_client.clone());
+
+    // Outside the retries, so every attempt carries the same token
+    if let Some(oauth2) = &options.oauth2 {
+        client = client.with(OAuth2Middleware::new(oauth2.clone(), base_client));
+    }
+
+    // Each hop is retried on its own
+    if options.redirects.follow {
+        client = client.with(RedirectMiddleware::new(options.redirects.clone()));
+    }
+
+    // Inside the redirects, so each hop gets its own host's cookies
+    if let Some(cookies) = &options.cookies {
+        client = client.with(CookieMiddleware::new(cookies.clone()));
+    }
+
+    if options.retries > 0 {
+        // Retry with increasing intervals between attempts.
+        let mut backoff = ExponentialBackoff::builder();
+        if let Some(max_delay) = options.retry_max_delay {
+            backoff = backoff.retry_bounds(max_delay.min(Duration::from_secs(1)), max_delay);
+        }
+        let retry_policy = backoff.build_with_max_retries(options.retries);
+        let strategy = RetryStatuses(options.retry_on.clone());
+
+        client = client.with(RetryReplayable(
+            RetryTransientMiddleware::new_with_policy_and_strategy(retry_policy, strategy),
+        ));
+    }
 
-    // Retry up to 3 times with increasing intervals between attempts.
-    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
+    // Innermost, so every retry shows up
+    if options.verbose {
+        let probe = options.tls_probe.then(|| options.tls.clone());
+        client = client.with(VerboseMiddleware::new(probe, proxy_routes));
+    }
 
-    ClientBuilder::new(base_client.unwrap())
-        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-        .build()
+    Ok(client.build())
 }
 
-pub async fn request_many(
-    client: &ClientWithMiddleware,
-    urls: &[String],
-    method: CliMethod,
-    body: Option<&str>,
-    headers: &[(String, String)],
-) -> Vec<anyhow::Result<HttpResult>> {
-    // Each item in the iterator becomes an async block that returns a future
-    let futures = urls.iter().map(|url| {
-        let client = client.clone(); // clone client so each future owns it
-        let url = url.clone();
-        let headers = headers.to_vec();
-        let body = body.map(|b| b.to_string());
-        let method = method.clone();
+// Decides which responses are retried. Connection failures and timeouts
+// always are; responses use the given statuses, or the defaults when empty.
+struct RetryStatuses(Vec<u16>);
+
+impl RetryableStrategy for RetryStatuses {
+    fn handle(&self, res: &reqwest_middleware::Result<Response>) -> Option<Retryable> {
+        match res {
+            Ok(resp) if self.0.is_empty() => default_on_request_success(resp),
+            Ok(resp) if self.0.contains(&resp.status().as_u16()) => Some(Retryable::Transient),
+            Ok(_) => None,
+            Err(e) => default_on_request_failure(e),
+        }
+    }
+}
+
+// The retry middleware refuses requests whose body can't be cloned, which is
+// the case for bodies streamed from disk. Those are sent once, without retries.
+struct RetryReplayable<M>(M);
+
+#[async_trait]
+impl<M: Middleware> Middleware for RetryReplayable<M> {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        if req.try_clone().is_some() {
+            self.0.handle(req, extensions, next).await
+        } else {
+            info!("Request: streaming body, not retrying");
+            next.run(req, extensions).await
+        }
+    }
+}
+
+// One request to send, with the status that counts as success when it
+// isn't just any 2xx
+#[derive(Debug, Clone)]
+pub struct RequestSpec {
+    pub url: String,
+    pub method: CliMethod,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<RequestBody>,
+    pub auth: Option<Auth>,
+    pub expect_status: Option<u16>,
+    // Save the body to a file instead of the output
+    pub download: Option<Download>,
+    // Digests the body must match, and whether to report one
+    pub digests: DigestOptions,
+    // Why it can't be sent, e.g. a bad URL read from stdin. It's reported as
+    // a failed result instead.
+    pub invalid: Option<String>,
+}
+
+// Send each request, yielding each result with its request as soon as it
+// can be written: in input order (holding back results that finish early)
+// or in completion order.
+pub fn request_stream<'a>(
+    client: &'a ClientWithMiddleware,
+    specs: impl Stream<Item = RequestSpec> + Send + 'a,
+    concurrency: &ConcurrencyOptions,
+) -> BoxStream<'a, (RequestSpec, anyhow::Result<HttpResult>)> {
+    let rate_limiter = concurrency.rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+    let host_limits = concurrency.per_host.map(|n| Arc::new(HostLimits::new(n)));
+    let progress = concurrency.progress.clone();
+
+    // Each request becomes an async block that returns a future
+    let futures = specs.map(move |spec| {
+        let rate_limiter = rate_limiter.clone();
+        let host_limits = host_limits.clone();
+        let progress = progress.clone();
 
         async move {
-            match method {
-                CliMethod::Get    => request(&client, &url, Method::GET,    None,              &headers).await,
-                CliMethod::Post   => request(&client, &url, Method::POST,   body.as_deref(),   &headers).await,
-                CliMethod::Put    => request(&client, &url, Method::PUT,    body.as_deref(),   &headers).await,
-                CliMethod::Delete => request(&client, &url, Method::DELETE, None,              &headers).await,
+            if let Some(reason) = &spec.invalid {
+                let res = Err(anyhow::anyhow!("{}", reason));
+                return (spec, res);
             }
+            // Held until the request finishes. A request waiting on its host
+            // still takes up one of the parallel slots.
+            let _host_permit = match &host_limits {
+                Some(limits) => Some(limits.acquire(&spec.url).await),
+                None => None,
+            };
+            if let Some(limiter) = &rate_limiter {
+                limiter.acquire().await;
+            }
+            // Methods without body semantics never send one
+            let body = spec.body.as_ref().filter(|_| spec.method.allows_body());
+            let transfer = progress.start(&spec.url);
+            let handling = ResponseHandling { download: spec.download.as_ref(), digests: &spec.digests, transfer: &transfer };
+            let res = request_with_progress(client, &spec.url, spec.method.to_method(), body, &spec.headers, spec.auth.as_ref(), handling).await;
+            transfer.finish();
+            (spec, res)
         }
     });
 
-    futures::future::join_all(futures).await
+    // Both run at most `parallel` at once
+    let parallel = concurrency.parallel.max(1);
+    if concurrency.ordered {
+        futures.buffered(parallel).boxed()
+    } else {
+        futures.buffer_unordered(parallel).boxed()
+    }
+}
+
+// Add a header unless it's already set (names compare case-insensitively)
+pub fn default_header(headers: &mut Vec<(String, String)>, key: &str, value: &str) {
+    if !headers.iter().any(|(k, _)| k.eq_ignore_ascii_case(key)) {
+        headers.push((key.to_string(), value.to_string()));
+    }
 }
 
-pub async fn request(client: &ClientWithMiddleware, url: &str, method: Method, body: Option<&str>, headers: &[(String, String)]) -> Result<HttpResult> {
+// One request, with the body returned rather than saved or checked. Downloads
+// go through request_stream, with a RequestSpec.
+pub async fn request(
+    client: &ClientWithMiddleware,
+    url: &str,
+    method: Method,
+    body: Option<&RequestBody>,
+    headers: &[(String, String)],
+    auth: Option<&Auth>,
+) -> Result<HttpResult> {
+    let handling = ResponseHandling { download: None, digests: &DigestOptions::default(), transfer: &Transfer::hidden() };
+    request_with_progress(client, url, method, body, headers, auth, handling).await
+}
+
+// What one request does with its response besides returning it
+struct ResponseHandling<'a> {
+    // Save the body to a file instead
+    download: Option<&'a Download>,
+    // Digests the body must match, and whether to report one
+    digests: &'a DigestOptions,
+    // Counts the bytes sent and received on a progress line
+    transfer: &'a Transfer,
+}
+
+// request, checking the body's digests and counting the bytes sent and
+// received on a progress line
+async fn request_with_progress(
+    client: &ClientWithMiddleware,
+    url: &str,
+    method: Method,
+    body: Option<&RequestBody>,
+    headers: &[(String, String)],
+    auth: Option<&Auth>,
+    handling: ResponseHandling<'_>,
+) -> Result<HttpResult> {
+    let ResponseHandling { download, digests, transfer } = handling;
     info!("Request: method = {}", method);
-    let mut builder = client.request(method, url);
+    let is_head = method == Method::HEAD;
 
-    // Add headers
-    info!("Request: adding headers");
-    for (key, value) in headers {
-        builder = builder.header(key, value);
-    }
+    // A resumed download asks for the rest of the file
+    let download = match download {
+        Some(download) if !is_head => Some(download.prepare(url).await?),
+        _ => None,
+    };
+    let mut headers = headers.to_vec();
+    headers.extend(download.iter().flat_map(|d| d.headers()));
+    let headers = headers.as_slice();
+
+    // An Authorization header given with -H wins over the auth options
+    let auth = auth.filter(|_| !headers.iter().any(|(k, _)| k.eq_ignore_ascii_case(AUTHORIZATION.as_str())));
 
-    info!("Request: checking body");
-    if let Some(b) = body {
-        builder = builder.body(b.to_string());
-    }
     let start_time = Instant::now();
 
-    info!("Request: calling send");
-    let resp = builder.send().await?;
+    // A download split into ranges stands in for the GET, with the HEAD
+    // response that sized it as the response
+    let segmented = match &download {
+        Some(download) if method == Method::GET => download.fetch_segments(client, url, headers, auth, transfer).await?,
+        _ => None,
+    };
+
+    let (mut resp, segmented) = match segmented {
+        Some((head, saved)) => (head, Some(saved)),
+        None => {
+            info!("Request: calling send");
+            let auth_headers = auth_headers(auth, &method, url, headers, body).await?;
+            let mut resp = send(client, url, &method, body, headers, auth_headers, transfer).await?;
+
+            // Digest needs the server's challenge, so answer the 401 and send again
+            if let Some(Auth::Digest { user, password }) = auth
+                && resp.status() == StatusCode::UNAUTHORIZED
+                && let Some(challenge) = DigestChallenge::from_headers(resp.headers()) {
+                info!("Request: answering Digest challenge");
+                let authorization = challenge.respond(user, password, &method, url, body)?;
+                resp = send(client, url, &method, body, headers, vec![(AUTHORIZATION.to_string(), authorization)], transfer).await?;
+            }
+            (resp, None)
+        }
+    };
+
+    let time_to_first_byte = start_time.elapsed();
+    let url = resp.url().to_string();
     let status = resp.status();
     let headers = resp.headers().clone();
-    let content_length = resp.content_length();
-    let body = resp.text().await?;
+    let content_length = segmented.as_ref().map(|saved| saved.size).or(resp.content_length());
+    let redirects = resp.extensions_mut().remove::<RedirectChain>().unwrap_or_default().0;
+    let wanted = segmented.is_some() || download.as_ref().is_some_and(|d| d.wants(status));
+
+    // Only the body that was asked for is checked. The digest headers only
+    // describe a whole body, and not once reqwest has decompressed it (which
+    // drops Content-Length).
+    let mut digest = match is_head || !(status.is_success() || wanted) {
+        true => BodyDigest::default(),
+        false => digests.start(&headers, status == StatusCode::OK && segmented.is_none() && headers.contains_key(CONTENT_LENGTH)),
+    };
+
+    // A HEAD response never has a body, so don't wait on one. Downloads
+    // stream to disk; error responses to them are read like any other.
+    let (body, saved) = match (segmented, download) {
+        (Some(saved), _) => {
+            // The ranges arrived out of order, so hash the file they made
+            digest.update_from_file(saved.path.as_ref()).await?;
+            (Bytes::new(), Some(saved))
+        }
+        (None, Some(download)) if wanted => (Bytes::new(), Some(download.save(resp, &mut digest, transfer).await?)),
+        _ if is_head => (Bytes::new(), None),
+        _ => (read_body(resp, &mut digest, transfer).await?, None),
+    };
+
+    // A download that fails its check isn't left in place
+    let digest = match digest.finish() {
+        Ok(digest) => digest,
+        Err(mismatch) => {
+            if let Some(saved) = &saved {
+                let _ = tokio::fs::remove_file(&saved.path).await;
+            }
+            return Err(mismatch.into());
+        }
+    };
 
     let latency = start_time.elapsed();
 
     info!("Request: returning result");
     Ok(HttpResult {
+        method,
+        url,
         status,
         headers,
         content_length,
         body,
+        time_to_first_byte,
         latency,
+        redirects,
+        saved,
+        digest,
     })
 }
 
+// Authorization for one request: signed for SigV4, or the preemptive header.
+// Digest has nothing to send until the server's challenged.
+pub(crate) async fn auth_headers(
+    auth: Option<&Auth>,
+    method: &Method,
+    url: &str,
+    headers: &[(String, String)],
+    body: Option<&RequestBody>,
+) -> Result<Vec<(String, String)>> {
+    Ok(match auth {
+        Some(Auth::AwsSigV4(signer)) => signer.sign(method, url, headers, body).await?,
+        Some(auth) => auth.preemptive_header().map(|h| vec![(AUTHORIZATION.to_string(), h)]).unwrap_or_default(),
+        None => Vec::new(),
+    })
+}
+
+pub(crate) async fn send(
+    client: &ClientWithMiddleware,
+    url: &str,
+    method: &Method,
+    body: Option<&RequestBody>,
+    headers: &[(String, String)],
+    auth_headers: Vec<(String, String)>,
+    transfer: &Transfer,
+) -> Result<Response> {
+    let mut builder = client.request(method.clone(), url);
+
+    // Add headers
+    info!("Request: adding headers");
+    for (key, value) in headers {
+        builder = builder.header(key, value);
+    }
+
+    for (key, value) in auth_headers {
+        // Sensitive values are left out of debug output
+        let mut value = HeaderValue::from_str(&value)?;
+        value.set_sensitive(true);
+        builder = builder.header(key, value);
+    }
+
+    info!("Request: checking body");
+    if let Some(b) = body {
+        // Streamed bodies would otherwise go out chunked
+        if let RequestBody::File { len, .. } = b {
+            builder = builder.header(CONTENT_LENGTH, *len);
+            transfer.begin(Direction::Upload, 0, Some(*len));
+        }
+        builder = builder.body(b.to_reqwest(transfer).await?);
+    }
+
+    Ok(builder.send().await?)
+}
+
+// The whole body, hashed and counted as it arrives
+async fn read_body(resp: Response, digest: &mut BodyDigest, transfer: &Transfer) -> Result<Bytes> {
+    transfer.begin(Direction::Download, 0, resp.content_length());
+
+    let mut body = BytesMut::with_capacity(resp.content_length().unwrap_or(0).min(1 << 24) as usize);
+    let mut stream = resp.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        digest.update(&chunk);
+        transfer.advance(chunk.len());
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body.freeze())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -108,7 +557,7 @@ mod tests {
         // Start a mock server on a random local port
         let server = MockServer::start_async().await;
 
-        let client = make_client();
+        let client = make_client(&ClientOptions::default()).unwrap();
         let mock = build_get_mock(&server, "").await;
 
         let url = format!("{}/get", server.base_url());
@@ -119,17 +568,39 @@ mod tests {
         ];
 
         // Call your own request function
-        let http_result = request(&client, &url, reqwest::Method::GET, None, &headers)
+        let http_result = request(&client, &url, reqwest::Method::GET, None, &headers, None)
             .await
             .unwrap();
 
         // Verify body contains mocked JSON
-        assert!(http_result.body.contains("\"url\": \"http://localhost/get\""));
+        assert!(http_result.text().contains("\"url\": \"http://localhost/get\""));
 
         // Verify that the mock was actually called
         mock.assert_async().await;
     }
 
+    fn spec(url: String, method: CliMethod) -> RequestSpec {
+        RequestSpec {
+            url,
+            method,
+            headers: Vec::new(),
+            body: None,
+            auth: None,
+            expect_status: None,
+            download: None,
+            digests: DigestOptions::default(),
+            invalid: None,
+        }
+    }
+
+    // The results of the specs, in order
+    async fn send_all(client: &ClientWithMiddleware, specs: Vec<RequestSpec>, concurrency: &ConcurrencyOptions) -> Vec<anyhow::Result<HttpResult>> {
+        request_stream(client, futures::stream::iter(specs), concurrency)
+            .map(|(_, res)| res)
+            .collect()
+            .await
+    }
+
     async fn build_get_mock<'a>(server: &'a MockServer, trailer: &str) -> Mock<'a> {
         server
         .mock_async(|when, then| {
@@ -145,33 +616,34 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_get_request_many_returns_bodys_mock() {
+    async fn test_get_request_stream_returns_bodys_mock() {
         // Start a mock server on a random local port
         let server = MockServer::start_async().await;
 
         let mock_1 = build_get_mock(&server, "_1").await;
         let mock_2 = build_get_mock(&server, "_2").await;
 
-        let client = make_client();
-
-        let mut urls: Vec<String> = Vec::new();
-        urls.push(format!("{}/get_1", server.base_url()));
-        urls.push(format!("{}/get_2", server.base_url()));
+        let client = make_client(&ClientOptions::default()).unwrap();
 
         let headers = vec![
             ("Accept".to_string(), "application/json".to_string()),
             ("User-Agent".to_string(), "rusty_curl_test".to_string()),
         ];
+        let specs = ["_1", "_2"]
+            .map(|trailer| RequestSpec {
+                headers: headers.clone(),
+                ..spec(format!("{}/get{}", server.base_url(), trailer), CliMethod::Get)
+            })
+            .to_vec();
 
         // Call your own request function
-        let http_results = request_many(&client, &urls, CliMethod::Get, None, &headers)
-            .await;
+        let http_results = send_all(&client, specs, &ConcurrencyOptions::default()).await;
 
         let http_result_1 = http_results[0].as_ref().expect("First request failed");
-        assert!(http_result_1.body.contains("\"url\": \"http://localhost/get_1\""));
+        assert!(http_result_1.text().contains("\"url\": \"http://localhost/get_1\""));
 
         let http_result_2 = http_results[1].as_ref().expect("First request failed");
-        assert!(http_result_2.body.contains("\"url\": \"http://localhost/get_2\""));
+        assert!(http_result_2.text().contains("\"url\": \"http://localhost/get_2\""));
 
         // Verify that the mock was actually called
         mock_1.assert_async().await;
@@ -180,6 +652,142 @@ mod tests {
         mock_2.assert_async().await;
     }
 
+    #[tokio::test]
+    async fn test_request_stream_keeps_input_order_mock() {
+        let server = MockServer::start_async().await;
+
+        server.mock_async(|when, then| {
+            when.method(GET).path("/slow");
+            then.status(200).body("slow").delay(Duration::from_millis(200));
+        }).await;
+        server.mock_async(|when, then| {
+            when.method(GET).path("/fast");
+            then.status(200).body("fast");
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let specs = vec![
+            spec(format!("{}/slow", server.base_url()), CliMethod::Get),
+            spec(format!("{}/fast", server.base_url()), CliMethod::Get),
+        ];
+
+        let results = send_all(&client, specs, &ConcurrencyOptions::default()).await;
+
+        assert_eq!(results[0].as_ref().unwrap().body, "slow");
+        assert_eq!(results[1].as_ref().unwrap().body, "fast");
+    }
+
+    #[tokio::test]
+    async fn test_request_stream_completion_order_mock() {
+        let server = MockServer::start_async().await;
+
+        server.mock_async(|when, then| {
+            when.method(GET).path("/slow");
+            then.status(200).body("slow").delay(Duration::from_millis(200));
+        }).await;
+        server.mock_async(|when, then| {
+            when.method(GET).path("/fast");
+            then.status(200).body("fast");
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let specs = futures::stream::iter(["slow", "fast"].map(|path| RequestSpec {
+            url: format!("{}/{}", server.base_url(), path),
+            method: CliMethod::Get,
+            headers: Vec::new(),
+            body: None,
+            auth: None,
+            expect_status: None,
+            download: None,
+            digests: DigestOptions::default(),
+            invalid: None,
+        }));
+        let concurrency = ConcurrencyOptions { ordered: false, ..Default::default() };
+
+        let results: Vec<_> = request_stream(&client, specs, &concurrency)
+            .collect()
+            .await;
+
+        // The fast one finishes first and comes with its request
+        assert!(results[0].0.url.ends_with("/fast"));
+        assert_eq!(results[0].1.as_ref().unwrap().body, "fast");
+        assert!(results[1].0.url.ends_with("/slow"));
+    }
+
+    #[tokio::test]
+    async fn test_request_stream_reports_invalid_specs_without_sending() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock_async(|when, then| {
+            when.any_request();
+            then.status(200);
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let specs = futures::stream::iter([
+            (server.url("/ok"), None),
+            (server.url("/skipped"), Some("Invalid URL at stdin line 2".to_string())),
+        ].map(|(url, invalid)| RequestSpec {
+            url,
+            method: CliMethod::Get,
+            headers: Vec::new(),
+            body: None,
+            auth: None,
+            expect_status: None,
+            download: None,
+            digests: DigestOptions::default(),
+            invalid,
+        }));
+
+        let results: Vec<_> = request_stream(&client, specs, &ConcurrencyOptions::default())
+            .collect()
+            .await;
+
+        assert!(results[0].1.is_ok());
+        assert_eq!(results[1].1.as_ref().err().unwrap().to_string(), "Invalid URL at stdin line 2");
+        mock.assert_hits_async(1).await;
+    }
+
+    #[tokio::test]
+    async fn test_request_stream_parallel_limit_mock() {
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock_async(|when, then| {
+            when.method(GET).path("/delay");
+            then.status(200).delay(Duration::from_millis(100));
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let specs = vec![spec(format!("{}/delay", server.base_url()), CliMethod::Get); 3];
+        let concurrency = ConcurrencyOptions { parallel: 1, ..Default::default() };
+
+        let start = Instant::now();
+        let results = send_all(&client, specs, &concurrency).await;
+
+        // One at a time, so the delays add up
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(results.iter().all(|r| r.is_ok()));
+        mock.assert_hits_async(3).await;
+    }
+
+    #[tokio::test]
+    async fn test_request_stream_per_host_limit_mock() {
+        let server = MockServer::start_async().await;
+
+        server.mock_async(|when, then| {
+            when.method(GET).path("/delay");
+            then.status(200).delay(Duration::from_millis(100));
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let specs = vec![spec(format!("{}/delay", server.base_url()), CliMethod::Get); 3];
+        let concurrency = ConcurrencyOptions { per_host: Some(1), ..Default::default() };
+
+        let start = Instant::now();
+        send_all(&client, specs, &concurrency).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
     #[tokio::test]
     async fn test_post_request_returns_body_mock() {
         // 1. Start a local mock server
@@ -197,19 +805,19 @@ mod tests {
         });
 
         // 3. Prepare the request
-        let client = make_client();
+        let client = make_client(&ClientOptions::default()).unwrap();
         let url = format!("{}/submit", &server.base_url());
         let headers = vec![("Content-Type".into(), "application/json".into())];
-        let body = Some(r#"{"hello":"world"}"#);
+        let body = RequestBody::from(r#"{"hello":"world"}"#);
 
         // 4. Call your request function
-        let http_result = request(&client, &url, Method::POST, body, &headers)
+        let http_result = request(&client, &url, Method::POST, Some(&body), &headers, None)
             .await
             .expect("Request should succeed");
 
         // 5. Verify the response your code processed
         assert_eq!(http_result.status.as_u16(), 201);
-        assert!(http_result.body.contains(r#""status":"ok""#));
+        assert!(http_result.text().contains(r#""status":"ok""#));
         // Verify that the mock was actually called
         mock.assert_async().await;
     }
@@ -231,19 +839,19 @@ mod tests {
         });
 
         // 3. Prepare the request
-        let client = make_client();
+        let client = make_client(&ClientOptions::default()).unwrap();
         let url = format!("{}/submit", &server.base_url());
         let headers = vec![("Content-Type".into(), "application/json".into())];
-        let body = Some(r#"{"hello":"world"}"#);
+        let body = RequestBody::from(r#"{"hello":"world"}"#);
 
         // 4. Call your request function
-        let http_result = request(&client, &url, Method::PUT, body, &headers)
+        let http_result = request(&client, &url, Method::PUT, Some(&body), &headers, None)
             .await
             .expect("Request should succeed");
 
         // 5. Verify the response your code processed
         assert_eq!(http_result.status.as_u16(), 201);
-        assert!(http_result.body.contains(r#""status":"ok""#));
+        assert!(http_result.text().contains(r#""status":"ok""#));
         // Verify that the mock was actually called
         mock.assert_async().await;
     }
@@ -264,7 +872,7 @@ mod tests {
                 .body(r#"{ "url": "http://localhost/delete" }"#);
         }).await;
 
-        let client = make_client();
+        let client = make_client(&ClientOptions::default()).unwrap();
         let url = format!("{}/delete", server.base_url());
 
         let headers = vec![
@@ -273,15 +881,444 @@ mod tests {
         ];
 
         // Call your own request function
-        let http_result = request(&client, &url, reqwest::Method::DELETE, None, &headers)
+        let http_result = request(&client, &url, reqwest::Method::DELETE, None, &headers, None)
             .await
             .unwrap();
 
         // Verify body contains mocked JSON
-        assert!(http_result.body.contains("\"url\": \"http://localhost/delete\""));
+        assert!(http_result.text().contains("\"url\": \"http://localhost/delete\""));
 
         // Verify that the mock was actually called
         mock.assert_async().await;
     }
 
+    #[tokio::test]
+    async fn test_retry_on_listed_status_mock() {
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock_async(|when, then| {
+            when.method(GET).path("/flaky");
+            then.status(409);
+        }).await;
+
+        let options = ClientOptions {
+            retries: 2,
+            retry_max_delay: Some(Duration::from_millis(1)),
+            retry_on: vec![409],
+            ..Default::default()
+        };
+        let client = make_client(&options).unwrap();
+        let url = format!("{}/flaky", server.base_url());
+
+        let http_result = request(&client, &url, Method::GET, None, &[], None).await.unwrap();
+
+        assert_eq!(http_result.status.as_u16(), 409);
+        // The first attempt plus two retries
+        mock.assert_hits_async(3).await;
+    }
+
+    #[tokio::test]
+    async fn test_no_retries_mock() {
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock_async(|when, then| {
+            when.method(POST).path("/create");
+            then.status(503);
+        }).await;
+
+        let options = ClientOptions { retries: 0, ..Default::default() };
+        let client = make_client(&options).unwrap();
+        let url = format!("{}/create", server.base_url());
+
+        let http_result = request(&client, &url, Method::POST, None, &[], None).await.unwrap();
+
+        assert_eq!(http_result.status.as_u16(), 503);
+        mock.assert_hits_async(1).await;
+    }
+
+    #[tokio::test]
+    async fn test_max_time_times_out_mock() {
+        let server = MockServer::start_async().await;
+
+        server.mock_async(|when, then| {
+            when.method(GET).path("/slow");
+            then.status(200).delay(Duration::from_millis(500));
+        }).await;
+
+        let options = ClientOptions {
+            timeout: Duration::from_millis(50),
+            retries: 0,
+            ..Default::default()
+        };
+        let client = make_client(&options).unwrap();
+        let url = format!("{}/slow", server.base_url());
+
+        let result = request(&client, &url, Method::GET, None, &[], None).await;
+
+        assert!(result.is_err(), "Expected the request to time out");
+    }
+
+    #[tokio::test]
+    async fn test_head_request_skips_body_mock() {
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock_async(|when, then| {
+            when.method(httpmock::Method::HEAD)
+                .path("/head");
+
+            then.status(200)
+                .header("Content-Length", "42");
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let url = format!("{}/head", server.base_url());
+
+        let http_result = request(&client, &url, Method::HEAD, None, &[], None)
+            .await
+            .unwrap();
+
+        assert_eq!(http_result.status.as_u16(), 200);
+        assert!(http_result.body.is_empty());
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_binary_body_kept_as_sent_mock() {
+        let server = MockServer::start_async().await;
+        let png: Vec<u8> = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0xff];
+        server.mock_async(|when, then| {
+            when.path("/logo.png");
+            then.status(200).header("Content-Type", "image/png").body(png.clone());
+        }).await;
+        server.mock_async(|when, then| {
+            when.path("/latin1");
+            then.status(200).header("Content-Type", "text/plain; charset=ISO-8859-1").body(b"caf\xe9");
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let image = request(&client, &server.url("/logo.png"), Method::GET, None, &[], None).await.unwrap();
+        let text = request(&client, &server.url("/latin1"), Method::GET, None, &[], None).await.unwrap();
+
+        assert_eq!(image.body, png);
+        assert!(image.is_binary());
+        assert_eq!(text.text(), "café");
+        assert!(!text.is_binary());
+    }
+
+    #[tokio::test]
+    async fn test_progress_counts_both_ways_mock() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.method(POST).path("/upload").body("abcdefghijklmnopqrstuvwxyz");
+            then.status(200).body("0123456789");
+        }).await;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"abcdefghijklmnopqrstuvwxyz").unwrap();
+        let body = RequestBody::from_file(file.path(), 0).unwrap();
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let upload = Transfer::hidden();
+        let counted = Transfer::hidden();
+        send(&client, &server.url("/upload"), &Method::POST, Some(&body), &[], Vec::new(), &upload).await.unwrap();
+        let handling = ResponseHandling { download: None, digests: &DigestOptions::default(), transfer: &counted };
+        let http_result = request_with_progress(&client, &server.url("/upload"), Method::POST, Some(&body), &[], None, handling).await.unwrap();
+
+        assert_eq!(upload.position(), 26);
+        assert_eq!(counted.position(), 10);
+        assert_eq!(http_result.body, "0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_checksum_checked_while_downloading_mock() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.path("/hello.txt");
+            then.status(200).body("hello");
+        }).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello.txt");
+        let download = Download { to: crate::download::SaveTo::Path(path.clone()), resume: false, segments: 1 };
+        let digests = |hex: &str| DigestOptions {
+            checksum: Some(crate::checksum::Checksum::parse(&format!("sha1:{}", hex)).unwrap()),
+            print: true,
+        };
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let fetch = |digests| {
+            let (client, url, download) = (&client, server.url("/hello.txt"), &download);
+            async move {
+                let handling = ResponseHandling { download: Some(download), digests: &digests, transfer: &Transfer::hidden() };
+                request_with_progress(client, &url, Method::GET, None, &[], None, handling).await
+            }
+        };
+
+        let http_result = fetch(digests("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d")).await.unwrap();
+        assert_eq!(http_result.digest.as_deref(), Some("sha1:aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"));
+        assert!(path.exists());
+
+        let e = fetch(digests("0000000000000000000000000000000000000000")).await.err().unwrap();
+        assert!(e.is::<crate::checksum::ChecksumMismatch>());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_patch_and_custom_request_stream_mock() {
+        let server = MockServer::start_async().await;
+
+        let patch_mock = server.mock_async(|when, then| {
+            when.method(httpmock::Method::PATCH)
+                .path("/patch")
+                .body("partial");
+            then.status(200);
+        }).await;
+
+        // httpmock can't match on custom verbs, so just match the path
+        let purge_mock = server.mock_async(|when, then| {
+            when.path("/purge");
+            then.status(200);
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+
+        let patch = RequestSpec {
+            body: Some(RequestBody::from("partial")),
+            ..spec(format!("{}/patch", server.base_url()), CliMethod::Patch)
+        };
+        let results = send_all(&client, vec![patch], &ConcurrencyOptions::default()).await;
+        assert!(results[0].is_ok());
+
+        let purge = spec(format!("{}/purge", server.base_url()), "PURGE".parse().unwrap());
+        let results = send_all(&client, vec![purge], &ConcurrencyOptions::default()).await;
+        assert!(results[0].is_ok());
+
+        patch_mock.assert_async().await;
+        purge_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_stream_per_request_specs_mock() {
+        let server = MockServer::start_async().await;
+
+        let get_mock = server.mock_async(|when, then| {
+            when.method(GET).path("/items").header("X-Trace", "get");
+            then.status(200);
+        }).await;
+        let post_mock = server.mock_async(|when, then| {
+            when.method(POST).path("/items").header("X-Trace", "post").body("new item");
+            then.status(201);
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let url = format!("{}/items", server.base_url());
+        let specs = futures::stream::iter(vec![
+            // A GET never sends its body
+            RequestSpec {
+                url: url.clone(),
+                method: CliMethod::Get,
+                headers: vec![("X-Trace".to_string(), "get".to_string())],
+                body: Some(RequestBody::from("dropped")),
+                auth: None,
+                expect_status: None,
+                download: None,
+                digests: DigestOptions::default(),
+                invalid: None,
+            },
+            RequestSpec {
+                url: url.clone(),
+                method: CliMethod::Post,
+                headers: vec![("X-Trace".to_string(), "post".to_string())],
+                body: Some(RequestBody::from("new item")),
+                auth: None,
+                expect_status: Some(201),
+                download: None,
+                digests: DigestOptions::default(),
+                invalid: None,
+            },
+        ]);
+
+        let results: Vec<_> = request_stream(&client, specs, &ConcurrencyOptions::default())
+            .collect()
+            .await;
+
+        assert_eq!(results[0].1.as_ref().unwrap().status, 200);
+        assert_eq!(results[1].0.expect_status, Some(201));
+        assert_eq!(results[1].1.as_ref().unwrap().status, 201);
+        get_mock.assert_async().await;
+        post_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_mock() {
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock_async(|when, then| {
+            when.method(GET).path("/private").header("Authorization", "Basic dXNlcjpwYXNz");
+            then.status(200);
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let url = format!("{}/private", server.base_url());
+        let auth = Auth::Basic { user: "user".to_string(), password: "pass".to_string() };
+
+        let http_result = request(&client, &url, Method::GET, None, &[], Some(&auth)).await.unwrap();
+
+        assert_eq!(http_result.status, 200);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_digest_auth_round_trip_mock() {
+        let server = MockServer::start_async().await;
+
+        let challenge_mock = server.mock_async(|when, then| {
+            when.method(POST)
+                .path("/digest")
+                .matches(|req| !req.headers.iter().flatten().any(|(k, _)| k.eq_ignore_ascii_case("authorization")));
+            then.status(401)
+                .header("WWW-Authenticate", r#"Digest realm="test", qop="auth", nonce="abc123", opaque="xyz""#);
+        }).await;
+        let answer_mock = server.mock_async(|when, then| {
+            when.method(POST)
+                .path("/digest")
+                .body("payload")
+                .matches(|req| req.headers.iter().flatten().any(|(k, v)| {
+                    k.eq_ignore_ascii_case("authorization")
+                        && v.starts_with(r#"Digest username="user", realm="test", nonce="abc123", uri="/digest""#)
+                        && v.contains(r#"opaque="xyz""#)
+                }));
+            then.status(200).body("in");
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let url = format!("{}/digest", server.base_url());
+        let body = RequestBody::from("payload");
+        let auth = Auth::Digest { user: "user".to_string(), password: "pass".to_string() };
+
+        let http_result = request(&client, &url, Method::POST, Some(&body), &[], Some(&auth)).await.unwrap();
+
+        assert_eq!(http_result.status, 200);
+        assert_eq!(http_result.body, "in");
+        challenge_mock.assert_async().await;
+        answer_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_aws_sigv4_signs_request_mock() {
+        use crate::sigv4::{AwsCredentials, AwsScope, SigV4};
+
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock_async(|when, then| {
+            when.method(PUT)
+                .path("/bucket/key")
+                .header_exists("X-Amz-Date")
+                .header("X-Amz-Content-Sha256", "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+                .matches(|req| req.headers.iter().flatten().any(|(k, v)| {
+                    k.eq_ignore_ascii_case("authorization")
+                        && v.starts_with("AWS4-HMAC-SHA256 Credential=minioadmin/")
+                        && v.contains("/us-east-1/s3/aws4_request, SignedHeaders=content-type;host;x-amz-content-sha256;x-amz-date, ")
+                }));
+            then.status(200);
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let url = format!("{}/bucket/key", server.base_url());
+        let body = RequestBody::from("hello");
+        let headers = vec![("Content-Type".to_string(), "text/plain".to_string())];
+        let auth = Auth::AwsSigV4(SigV4 {
+            scope: AwsScope { region: "us-east-1".to_string(), service: "s3".to_string() },
+            credentials: AwsCredentials {
+                access_key_id: "minioadmin".to_string(),
+                secret_access_key: "minioadmin".to_string(),
+                session_token: None,
+            },
+        });
+
+        let http_result = request(&client, &url, Method::PUT, Some(&body), &headers, Some(&auth)).await.unwrap();
+
+        assert_eq!(http_result.status, 200);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_header_authorization_wins_over_auth_mock() {
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock_async(|when, then| {
+            when.method(GET).path("/private").header("Authorization", "Bearer from-header");
+            then.status(200);
+        }).await;
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let url = format!("{}/private", server.base_url());
+        let headers = vec![("Authorization".to_string(), "Bearer from-header".to_string())];
+        let auth = Auth::Bearer("from-option".to_string());
+
+        let http_result = request(&client, &url, Method::GET, None, &headers, Some(&auth)).await.unwrap();
+
+        assert_eq!(http_result.status, 200);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_post_multipart_form_mock() {
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock_async(|when, then| {
+            when.method(POST)
+                .path("/form")
+                .header_exists("Content-Type")
+                .body_contains("Content-Disposition: form-data; name=\"user\"\r\n\r\nsome_user\r\n");
+            then.status(200);
+        }).await;
+
+        let (body, content_type) = crate::form::multipart(&["user=some_user".to_string()]).unwrap();
+        let headers = vec![("Content-Type".to_string(), content_type)];
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let url = format!("{}/form", server.base_url());
+
+        let http_result = request(&client, &url, Method::POST, Some(&body), &headers, None)
+            .await
+            .expect("Form request should succeed");
+
+        assert_eq!(http_result.status.as_u16(), 200);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_post_streamed_file_body_mock() {
+        use std::io::Write;
+
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock_async(|when, then| {
+            when.method(POST)
+                .path("/upload")
+                .header("Content-Length", "26")
+                .body("abcdefghijklmnopqrstuvwxyz");
+            then.status(200);
+        }).await;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"abcdefghijklmnopqrstuvwxyz").unwrap();
+
+        // A zero threshold forces the file to be streamed
+        let body = RequestBody::from_file(file.path(), 0).unwrap();
+        assert!(matches!(body, RequestBody::File { .. }));
+
+        let client = make_client(&ClientOptions::default()).unwrap();
+        let url = format!("{}/upload", server.base_url());
+
+        let http_result = request(&client, &url, Method::POST, Some(&body), &[], None)
+            .await
+            .expect("Streamed request should succeed");
+
+        assert_eq!(http_result.status.as_u16(), 200);
+        mock.assert_async().await;
+    }
+
 }
@@ -1,3 +1,20 @@
 pub mod http;       // expose your http.rs module
 pub mod output;     // add other modules you want to access in tests
 pub mod cli;        // same idea if needed
+pub mod body;
+pub mod form;
+pub mod limit;
+pub mod urls;
+pub mod batch;
+pub mod auth;
+pub mod netrc;
+pub mod oauth2;
+pub mod sigv4;
+pub mod tls;
+pub mod verbose;
+pub mod proxy;
+pub mod redirect;
+pub mod cookies;
+pub mod download;
+pub mod checksum;
+pub mod progress;
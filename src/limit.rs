@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, sleep};
+
+// A token bucket holding at most one token, so requests are spread evenly
+// instead of going out in bursts.
+pub struct RateLimiter {
+    per_second: f64,
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: f64) -> RateLimiter {
+        RateLimiter {
+            per_second,
+            state: Mutex::new(Bucket { tokens: 1.0, last_refill: Instant::now() }),
+        }
+    }
+
+    // Wait until a request may go out
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.state.lock().unwrap();
+                let now = Instant::now();
+                let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.per_second;
+                bucket.tokens = (bucket.tokens + refill).min(1.0);
+                bucket.last_refill = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second)
+            };
+            sleep(wait).await;
+        }
+    }
+}
+
+// Caps the number of requests in flight to any one host
+pub struct HostLimits {
+    per_host: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimits {
+    pub fn new(per_host: usize) -> HostLimits {
+        HostLimits { per_host, hosts: Mutex::new(HashMap::new()) }
+    }
+
+    // Wait for a slot on the URL's host. The slot is held until the permit is
+    // dropped. URLs that don't parse share one slot pool.
+    pub async fn acquire(&self, url: &str) -> OwnedSemaphorePermit {
+        let semaphore = {
+            let mut hosts = self.hosts.lock().unwrap();
+            hosts.entry(host_key(url))
+                .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+                .clone()
+        };
+
+        semaphore.acquire_owned().await.expect("host semaphore is never closed")
+    }
+}
+
+fn host_key(url: &str) -> String {
+    match url::Url::parse(url) {
+        Ok(url) => format!("{}:{}", url.host_str().unwrap_or_default(), url.port_or_known_default().unwrap_or_default()),
+        Err(_) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_key_includes_default_port() {
+        assert_eq!(host_key("https://example.com/a"), "example.com:443");
+        assert_eq!(host_key("http://example.com/b"), "example.com:80");
+        assert_eq!(host_key("http://example.com:8080/c"), "example.com:8080");
+        assert_eq!(host_key("not a url"), "");
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_spaces_requests() {
+        let limiter = RateLimiter::new(20.0);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+
+        // The first goes straight away, the next two 50ms apart
+        assert!(start.elapsed() >= Duration::from_millis(95));
+    }
+
+    #[tokio::test]
+    async fn host_limits_are_per_host() {
+        let limits = HostLimits::new(1);
+
+        let _a = limits.acquire("https://a.example.com/1").await;
+        // A different host isn't blocked by the held permit
+        let _b = limits.acquire("https://b.example.com/1").await;
+
+        let blocked = tokio::time::timeout(Duration::from_millis(20), limits.acquire("https://a.example.com/2")).await;
+        assert!(blocked.is_err(), "Expected the second request to a.example.com to wait");
+    }
+}
@@ -1,14 +1,34 @@
-mod cli;
-mod http;
-mod output;
-
 use anyhow::Result;
 use clap::{Parser as ClapParser};
-use log::{info};
+use std::io::{self, IsTerminal};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use log::{error, info};
+use tokio::io::{AsyncBufRead, BufReader};
+
+use rusty_curl::auth::Auth;
+use rusty_curl::batch::BatchEntry;
+use rusty_curl::body::RequestBody;
+use rusty_curl::checksum::DigestOptions;
+use rusty_curl::cli::{Cli, validate_cli};
+use rusty_curl::cookies::CookieOptions;
+use rusty_curl::download::{Download, SaveTo};
+use rusty_curl::form;
+use rusty_curl::netrc::Netrc;
+use rusty_curl::oauth2::OAuth2Options;
+use rusty_curl::output::{OutputOptions, ResultWriter, WriteOut, build_writer};
+use rusty_curl::http::{ClientOptions, ConcurrencyOptions, RequestSpec, default_header, make_client, request_stream};
+use rusty_curl::progress::Progress;
+use rusty_curl::proxy::ProxyOptions;
+use rusty_curl::redirect::RedirectOptions;
+use rusty_curl::tls::TlsOptions;
+use rusty_curl::urls::{check_url, read_urls};
 
-use crate::cli::{Cli, validate_cli};
-use crate::output::{build_writer, write_results};
-use crate::http::{make_client, request_many};
+// Exit status when a body doesn't match its checksum, so scripts can tell it
+// from a failed request
+const CHECKSUM_FAILED: i32 = 4;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -20,20 +40,234 @@ async fn main() -> Result<()> {
 
     validate_cli(&cli).check_and_exit()?;
 
-    let client = make_client();
+    // Ask for any missing password before sending anything
+    let auth = Auth::from_cli(&cli)?;
+    let netrc = Netrc::from_cli(&cli)?;
+    let cookies = CookieOptions::from_cli(&cli)?;
 
-    let body = cli.json.as_deref()
-        .or(cli.body.as_deref())
-        .or(cli.form.as_deref());
-    let results = request_many(&client, &cli.urls, cli.method, body, &cli.headers).await;
+    let client_options = ClientOptions {
+        timeout: cli.max_time.unwrap_or(ClientOptions::default().timeout),
+        connect_timeout: cli.connect_timeout,
+        retries: cli.retry,
+        retry_max_delay: cli.retry_max_delay,
+        retry_on: cli.retry_on.clone(),
+        oauth2: OAuth2Options::from_cli(&cli)?,
+        tls: TlsOptions::from_cli(&cli)?,
+        proxy: ProxyOptions::from_cli(&cli),
+        redirects: RedirectOptions {
+            follow: cli.location || cli.location_trusted,
+            max_redirs: cli.max_redirs,
+            post301: cli.post301,
+            post302: cli.post302,
+            post303: cli.post303,
+            location_trusted: cli.location_trusted,
+        },
+        cookies: cookies.clone(),
+        verbose: cli.verbose,
+        tls_probe: cli.tls_probe,
+    };
+    let client = make_client(&client_options)?;
+
+    let mut headers = cli.headers.clone();
+    let body = build_body(&cli)?.map(|(body, content_type)| {
+        if let Some(content_type) = content_type {
+            default_header(&mut headers, "Content-Type", &content_type);
+        }
+        body
+    });
+    if cli.json.is_some() {
+        default_header(&mut headers, "Accept", "application/json");
+    }
+    let concurrency = ConcurrencyOptions {
+        // A session's cookies only reach later requests if they wait
+        parallel: if cli.session { 1 } else { cli.parallel as usize },
+        rate: cli.rate,
+        per_host: cli.parallel_host.map(|n| n as usize),
+        ordered: !cli.completion_order,
+        // -v already fills stderr
+        progress: Progress::new(cli.silent || cli.verbose),
+    };
 
     let writer = build_writer(&cli.output)?;
 
-    let had_failure = write_results(cli.urls, results, writer, cli.latency)?;
+    let options = OutputOptions {
+        latency: cli.latency,
+        raw: cli.raw,
+        format: cli.output_format,
+        write_out: cli.write_out.as_deref().map(load_write_out).transpose()?,
+        // -o - is stdout on purpose
+        terminal: cli.output.is_none() && io::stdout().is_terminal(),
+    };
 
-    if had_failure {
+    // Write each result as soon as it's ready
+    let read_failed = Arc::new(AtomicBool::new(false));
+    let method = cli.method.clone();
+    let url_auth = auth.clone();
+    let mut saves = cli.save.clone().into_iter().map(|path| SaveTo::Path(path.into()));
+    let remote_name = cli.remote_name;
+    let resume = cli.continue_at.is_some();
+    let segments = cli.segments as u32;
+    let digests = DigestOptions::from_cli(&cli);
+    let batch_digests = digests.clone();
+    let mut specs = url_stream(&cli, &read_failed).await?
+        .map(move |(url, invalid)| RequestSpec {
+            // --save pairs with the command line URLs, which come first
+            download: saves.next()
+                .or(remote_name.then_some(SaveTo::RemoteName))
+                .map(|to| Download { to, resume, segments }),
+            auth: url_auth.clone(),
+            url,
+            method: method.clone(),
+            headers: headers.clone(),
+            body: body.clone(),
+            expect_status: None,
+            digests: digests.clone(),
+            invalid,
+        })
+        .boxed();
+    if let Some(path) = &cli.batch {
+        let batch = batch_stream(path, &cli.headers, &read_failed).await?
+            .map(move |spec| RequestSpec {
+                auth: auth.clone(),
+                download: remote_name.then_some(Download { to: SaveTo::RemoteName, resume, segments }),
+                digests: batch_digests.clone(),
+                ..spec
+            });
+        specs = specs.chain(batch).boxed();
+    }
+
+    // Hosts without other credentials get their own from the netrc file
+    if let Some(netrc) = netrc {
+        specs = specs
+            .map(move |spec| match spec.auth {
+                Some(_) => spec,
+                None => RequestSpec { auth: netrc.auth_for(&spec.url), ..spec },
+            })
+            .boxed();
+    }
+
+    let mut results = request_stream(&client, specs, &concurrency);
+    let mut result_writer = ResultWriter::new(writer, &options)?;
+    while let Some((spec, res)) = results.next().await {
+        concurrency.progress.suspend(|| result_writer.write_spec(&spec, res))?;
+    }
+
+    let checksum_failed = result_writer.checksum_failed();
+    let had_failure = result_writer.finish()?;
+
+    if let (Some(path), Some(cookies)) = (&cli.cookie_jar, &cookies) {
+        cookies.jar.lock().unwrap().save(path)?;
+    }
+
+    if checksum_failed {
+        std::process::exit(CHECKSUM_FAILED);
+    }
+    if had_failure || read_failed.load(Ordering::Relaxed) {
         std::process::exit(1);
     }
 
     Ok(())
 }
+
+// The URLs from the command line, then the URL file, then stdin, each with
+// why it can't be requested if it can't. The lists are read as requests go
+// out rather than up front.
+async fn url_stream(cli: &Cli, read_failed: &Arc<AtomicBool>) -> Result<BoxStream<'static, (String, Option<String>)>> {
+    let mut urls = stream::iter(cli.urls.clone()).map(|url| (url, None)).boxed();
+
+    if let Some(path) = &cli.url_file {
+        let file = tokio::fs::File::open(path).await?;
+        urls = urls.chain(url_lines(path.clone(), BufReader::new(file), read_failed.clone())).boxed();
+    }
+
+    if cli.urls_from_stdin {
+        let stdin = BufReader::new(tokio::io::stdin());
+        urls = urls.chain(url_lines("stdin".to_string(), stdin, read_failed.clone())).boxed();
+    }
+
+    Ok(urls)
+}
+
+fn url_lines<R>(source: String, reader: R, read_failed: Arc<AtomicBool>) -> BoxStream<'static, (String, Option<String>)>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+{
+    read_urls(reader)
+        .filter_map(move |line| {
+            let url = match line {
+                // The file was checked up front, stdin wasn't. A bad URL
+                // isn't sent; it's reported as a failed request.
+                Ok((line_no, url)) => {
+                    let invalid = check_url(&url, &source, line_no);
+                    Some((url, invalid))
+                }
+                Err(e) => {
+                    error!("Failed to read URLs from {}: {}", source, e);
+                    read_failed.store(true, Ordering::Relaxed);
+                    None
+                }
+            };
+            futures::future::ready(url)
+        })
+        .boxed()
+}
+
+// The requests in a batch file, read as they go out. Entries were checked up
+// front; one that still can't be turned into a request is reported and skipped.
+async fn batch_stream(path: &str, headers: &[(String, String)], read_failed: &Arc<AtomicBool>) -> Result<BoxStream<'static, RequestSpec>> {
+    let file = BufReader::new(tokio::fs::File::open(path).await?);
+    let path = path.to_string();
+    let headers = headers.to_vec();
+    let read_failed = read_failed.clone();
+
+    let specs = read_urls(file).filter_map(move |line| {
+        let spec = match line {
+            Ok((line_no, line)) => match BatchEntry::parse(&line).and_then(|entry| entry.into_spec(&headers)) {
+                Ok(spec) => Some(spec),
+                Err(e) => {
+                    error!("Skipping batch entry at {}:{}: {}", path, line_no, e);
+                    read_failed.store(true, Ordering::Relaxed);
+                    None
+                }
+            },
+            Err(e) => {
+                error!("Failed to read batch file {}: {}", path, e);
+                read_failed.store(true, Ordering::Relaxed);
+                None
+            }
+        };
+        futures::future::ready(spec)
+    });
+
+    Ok(specs.boxed())
+}
+
+// Build the request body and the Content-Type it implies, if any
+fn build_body(cli: &Cli) -> Result<Option<(RequestBody, Option<String>)>> {
+    if !cli.form.is_empty() {
+        let (body, content_type) = form::multipart(&cli.form)?;
+        return Ok(Some((body, Some(content_type))));
+    }
+
+    if !cli.data_urlencode.is_empty() {
+        let body = form::urlencoded(&cli.data_urlencode)?;
+        return Ok(Some((body, Some("application/x-www-form-urlencoded".to_string()))));
+    }
+
+    if let Some(json) = &cli.json {
+        return Ok(Some((RequestBody::load(json)?, Some("application/json".to_string()))));
+    }
+
+    cli.body.as_deref()
+        .map(|arg| Ok((RequestBody::load(arg)?, None)))
+        .transpose()
+}
+
+// Parse a --write-out template, reading it from a file for @file
+fn load_write_out(arg: &str) -> Result<WriteOut> {
+    let template = match arg.strip_prefix('@') {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => arg.to_string(),
+    };
+    WriteOut::parse(&template).map_err(anyhow::Error::msg)
+}
@@ -0,0 +1,287 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+
+use crate::auth::Auth;
+use crate::cli::Cli;
+
+// Credentials from a .netrc file, looked up by host
+#[derive(Debug, Default, PartialEq)]
+pub struct Netrc {
+    machines: Vec<(String, Login)>,
+    default: Option<Login>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Login {
+    login: Option<String>,
+    password: Option<String>,
+}
+
+impl Netrc {
+    // The netrc to use for --netrc, --netrc-optional and --netrc-file, if any.
+    // Only --netrc-optional lets the file be missing.
+    pub fn from_cli(cli: &Cli) -> Result<Option<Netrc>> {
+        Netrc::from_cli_in(cli, std::env::var_os("HOME").map(PathBuf::from))
+    }
+
+    // from_cli, with .netrc looked for in `home` rather than $HOME
+    pub fn from_cli_in(cli: &Cli, home: Option<PathBuf>) -> Result<Option<Netrc>> {
+        let path = match (&cli.netrc_file, cli.netrc || cli.netrc_optional) {
+            (Some(path), _) => PathBuf::from(path),
+            (None, true) => home.context("Can't find the netrc file: HOME isn't set")?.join(".netrc"),
+            (None, false) => return Ok(None),
+        };
+
+        if cli.netrc_optional && cli.netrc_file.is_none() && !path.exists() {
+            return Ok(None);
+        }
+
+        Netrc::load(&path).map(Some)
+    }
+
+    pub fn load(path: &Path) -> Result<Netrc> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Can't read netrc file {}", path.display()))?;
+        Netrc::parse(&contents).with_context(|| format!("Invalid netrc file {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> Result<Netrc> {
+        let mut netrc = Netrc::default();
+        // The entry the login and password tokens belong to
+        let mut current: Option<Login> = None;
+        let mut current_machine: Option<String> = None;
+        let mut tokens = Tokens::new(contents);
+
+        fn finish(netrc: &mut Netrc, machine: Option<String>, login: Option<Login>) {
+            if let Some(login) = login {
+                match machine {
+                    Some(machine) => netrc.machines.push((machine, login)),
+                    None => netrc.default = Some(login),
+                }
+            }
+        }
+
+        while let Some(token) = tokens.next() {
+            match token.as_str() {
+                "machine" => {
+                    finish(&mut netrc, current_machine.take(), current.take());
+                    current_machine = Some(tokens.value("machine")?);
+                    current = Some(Login::default());
+                }
+                "default" => {
+                    finish(&mut netrc, current_machine.take(), current.take());
+                    current = Some(Login::default());
+                }
+                "login" | "password" | "account" => {
+                    let value = tokens.value(&token)?;
+                    let Some(entry) = current.as_mut() else {
+                        bail!("{} before any machine or default", token);
+                    };
+                    match token.as_str() {
+                        "login" => entry.login = Some(value),
+                        "password" => entry.password = Some(value),
+                        _ => {}
+                    }
+                }
+                "macdef" => {
+                    tokens.value("macdef")?;
+                    tokens.skip_macro();
+                }
+                other => bail!("unexpected token `{}`", other),
+            }
+        }
+        finish(&mut netrc, current_machine, current);
+
+        Ok(netrc)
+    }
+
+    // Basic auth for the URL's host: the first matching machine, else the
+    // default entry. Entries without a login are ignored.
+    pub fn auth_for(&self, url: &str) -> Option<Auth> {
+        let host = url::Url::parse(url).ok()?.host_str()?.to_string();
+
+        let login = self.machines.iter()
+            .find(|(machine, _)| machine.eq_ignore_ascii_case(&host))
+            .map(|(_, login)| login)
+            .or(self.default.as_ref())?;
+
+        Some(Auth::Basic {
+            user: login.login.clone()?,
+            password: login.password.clone().unwrap_or_default(),
+        })
+    }
+}
+
+// Whitespace separated tokens. A token may be double quoted, with backslash
+// escapes, so passwords can hold spaces.
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(contents: &'a str) -> Self {
+        Tokens { rest: contents }
+    }
+
+    fn value(&mut self, token: &str) -> Result<String> {
+        self.next().with_context(|| format!("missing value after {}", token))
+    }
+
+    // A macro body runs from the line after macdef to the next blank line
+    fn skip_macro(&mut self) {
+        let mut lines = self.rest.split_inclusive('\n');
+        let mut skipped = lines.next().map_or(0, str::len);
+        for line in lines {
+            skipped += line.len();
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+        self.rest = &self.rest[skipped..];
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.rest = self.rest.trim_start();
+        // Lines starting with # are comments
+        while self.rest.starts_with('#') {
+            self.rest = self.rest.find('\n').map_or("", |end| &self.rest[end..]).trim_start();
+        }
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        if let Some(quoted) = self.rest.strip_prefix('"') {
+            let mut token = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => token.extend(chars.next().map(|(_, c)| match c {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        c => c,
+                    })),
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => token.push(c),
+                }
+            }
+            self.rest = &quoted[end..];
+            return Some(token);
+        }
+
+        let end = self.rest.find(char::is_whitespace).unwrap_or(self.rest.len());
+        let token = self.rest[..end].to_string();
+        self.rest = &self.rest[end..];
+        Some(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETRC: &str = "\
+# CI credentials
+machine api.example.com login ci password s3cret
+
+machine files.example.com
+    login uploader
+    password \"with space \\\"quoted\\\"\"
+
+macdef init
+machine evil.example.com login nope password nope
+
+default login anonymous password guest
+";
+
+    fn basic(user: &str, password: &str) -> Auth {
+        Auth::Basic { user: user.to_string(), password: password.to_string() }
+    }
+
+    #[test]
+    fn parse_machines_and_default() -> Result<()> {
+        let netrc = Netrc::parse(NETRC)?;
+
+        assert_eq!(netrc.auth_for("https://api.example.com/v1"), Some(basic("ci", "s3cret")));
+        assert_eq!(netrc.auth_for("https://FILES.example.com/up"), Some(basic("uploader", r#"with space "quoted""#)));
+        assert_eq!(netrc.auth_for("https://other.example.com"), Some(basic("anonymous", "guest")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_skips_macro_bodies() -> Result<()> {
+        let netrc = Netrc::parse(NETRC)?;
+
+        // The line inside the macro isn't an entry
+        assert_eq!(netrc.auth_for("https://evil.example.com"), Some(basic("anonymous", "guest")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_without_default() -> Result<()> {
+        let netrc = Netrc::parse("machine a.example.com login a password pa\nmachine b.example.com password only")?;
+
+        assert_eq!(netrc.auth_for("http://a.example.com:8080/x"), Some(basic("a", "pa")));
+        assert_eq!(netrc.auth_for("http://b.example.com"), None);
+        assert_eq!(netrc.auth_for("http://c.example.com"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Netrc::parse("machine").is_err());
+        assert!(Netrc::parse("login alice").is_err());
+        assert!(Netrc::parse("machine a.example.com user alice").is_err());
+    }
+
+    #[test]
+    fn from_cli_named_file_must_exist() -> Result<()> {
+        let cli = Cli { netrc_file: Some("/no/such/netrc".to_string()), ..Default::default() };
+        assert!(Netrc::from_cli(&cli).is_err());
+
+        // Without a netrc flag there's nothing to read
+        assert_eq!(Netrc::from_cli(&Cli::default())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn from_cli_optional_file_may_be_missing() -> Result<()> {
+        // A home without a .netrc
+        let home = tempfile::tempdir()?;
+        let home = Some(home.path().to_path_buf());
+
+        let optional = Cli { netrc_optional: true, ..Default::default() };
+        assert_eq!(Netrc::from_cli_in(&optional, home.clone())?, None);
+
+        let required = Cli { netrc: true, ..Default::default() };
+        assert!(Netrc::from_cli_in(&required, home.clone()).is_err());
+        assert!(Netrc::from_cli_in(&required, None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn from_cli_reads_netrc_in_home() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        std::fs::write(home.path().join(".netrc"), "machine a.example.com login a password pa")?;
+
+        let cli = Cli { netrc_optional: true, ..Default::default() };
+        let netrc = Netrc::from_cli_in(&cli, Some(home.path().to_path_buf()))?.unwrap();
+        assert_eq!(netrc.auth_for("https://a.example.com"), Some(basic("a", "pa")));
+
+        Ok(())
+    }
+}
@@ -1,10 +1,298 @@
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::{self, Write};
 
-use crate::http::HttpResult;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use url::Url;
 
+use crate::checksum::ChecksumMismatch;
+use crate::cli::OutputFormat;
+use crate::http::{HttpResult, RequestSpec, media_type};
+
+// How results are written
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    // Print each request's latency
+    pub latency: bool,
+    // Print bodies exactly as received, without pretty-printing JSON
+    pub raw: bool,
+    pub format: OutputFormat,
+    // Write one line per request from this template instead of the result
+    pub write_out: Option<WriteOut>,
+    // The output is a terminal, so binary bodies are held back
+    pub terminal: bool,
+}
+
+// A curl style --write-out template, e.g. "%{http_code} %{time_total}\n".
+// %{name} is a variable, %header{name} a response header, %% a literal %,
+// and \n, \r, \t and \\ are the usual escapes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteOut {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Var(Var),
+    Header(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Var {
+    HttpCode,
+    Method,
+    Url,
+    UrlEffective,
+    ContentType,
+    ContentLength,
+    SizeDownload,
+    NumHeaders,
+    TimeStartTransfer,
+    TimeTotal,
+    NumRedirects,
+    RedirectUrl,
+    FilenameEffective,
+    Digest,
+    ErrorMsg,
+}
+
+impl Var {
+    fn from_name(name: &str) -> Option<Var> {
+        let var = match name {
+            "http_code" | "response_code" => Var::HttpCode,
+            "method" => Var::Method,
+            "url" => Var::Url,
+            "url_effective" => Var::UrlEffective,
+            "content_type" => Var::ContentType,
+            "content_length" => Var::ContentLength,
+            "size_download" => Var::SizeDownload,
+            "num_headers" => Var::NumHeaders,
+            "time_starttransfer" => Var::TimeStartTransfer,
+            "time_total" => Var::TimeTotal,
+            "num_redirects" => Var::NumRedirects,
+            "redirect_url" => Var::RedirectUrl,
+            "filename_effective" => Var::FilenameEffective,
+            "digest" => Var::Digest,
+            "errormsg" => Var::ErrorMsg,
+            _ => return None,
+        };
+        Some(var)
+    }
+}
+
+impl WriteOut {
+    pub fn parse(template: &str) -> Result<WriteOut, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+
+        while let Some(c) = rest.chars().next() {
+            if let Some(after) = rest.strip_prefix("%{") {
+                let end = after.find('}').ok_or_else(|| format!("Unterminated variable in write-out `{}`", template))?;
+                let name = &after[..end];
+                let var = Var::from_name(name).ok_or_else(|| format!("Unknown write-out variable `{}`", name))?;
+                push_literal(&mut segments, &mut literal);
+                segments.push(Segment::Var(var));
+                rest = &after[end + 1..];
+            } else if let Some(after) = rest.strip_prefix("%header{") {
+                let end = after.find('}').ok_or_else(|| format!("Unterminated header in write-out `{}`", template))?;
+                push_literal(&mut segments, &mut literal);
+                segments.push(Segment::Header(after[..end].to_string()));
+                rest = &after[end + 1..];
+            } else if let Some(after) = rest.strip_prefix("%%") {
+                literal.push('%');
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('\\') {
+                match after.chars().next() {
+                    Some('n') => literal.push('\n'),
+                    Some('r') => literal.push('\r'),
+                    Some('t') => literal.push('\t'),
+                    Some('\\') => literal.push('\\'),
+                    // Not an escape we know, keep the backslash
+                    _ => {
+                        literal.push('\\');
+                        rest = after;
+                        continue;
+                    }
+                }
+                rest = &after[1..];
+            } else {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        push_literal(&mut segments, &mut literal);
+
+        Ok(WriteOut { segments })
+    }
+
+    // Fill in the template for one request. Failed requests still produce a
+    // line, with a 000 status like curl.
+    pub fn render(&self, url: &str, res: &anyhow::Result<HttpResult>) -> String {
+        let mut out = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Header(name) => {
+                    if let Ok(resp) = res
+                        && let Some(value) = resp.headers.get(name.as_str()) {
+                        out.push_str(&String::from_utf8_lossy(value.as_bytes()));
+                    }
+                }
+                Segment::Var(var) => out.push_str(&render_var(*var, url, res)),
+            }
+        }
+
+        out
+    }
+}
+
+fn push_literal(segments: &mut Vec<Segment>, literal: &mut String) {
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(std::mem::take(literal)));
+    }
+}
+
+fn render_var(var: Var, url: &str, res: &anyhow::Result<HttpResult>) -> String {
+    let resp = match (var, res) {
+        (Var::Url, _) => return url.to_string(),
+        (Var::HttpCode, Err(_)) => return "000".to_string(),
+        (Var::ErrorMsg, Err(e)) => return format!("{:#}", e),
+        (Var::TimeTotal | Var::TimeStartTransfer, Err(_)) => return "0.000000".to_string(),
+        (Var::SizeDownload | Var::NumHeaders | Var::NumRedirects, Err(_)) => return "0".to_string(),
+        (_, Err(_)) => return String::new(),
+        (_, Ok(resp)) => resp,
+    };
+
+    match var {
+        Var::HttpCode => format!("{:03}", resp.status.as_u16()),
+        Var::Method => resp.method.to_string(),
+        Var::Url => url.to_string(),
+        Var::UrlEffective => resp.url.clone(),
+        Var::ContentType => resp.headers
+            .get(CONTENT_TYPE)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .unwrap_or_default(),
+        Var::ContentLength => resp.content_length.map(|l| l.to_string()).unwrap_or_default(),
+        Var::SizeDownload => match &resp.saved {
+            Some(saved) => saved.bytes.to_string(),
+            None => resp.body.len().to_string(),
+        },
+        Var::NumHeaders => resp.headers.len().to_string(),
+        Var::TimeStartTransfer => format!("{:.6}", resp.time_to_first_byte.as_secs_f64()),
+        Var::TimeTotal => format!("{:.6}", resp.latency.as_secs_f64()),
+        Var::NumRedirects => resp.redirects.len().to_string(),
+        // Where a redirect that wasn't followed points
+        Var::RedirectUrl => match resp.status.is_redirection() {
+            true => resp.headers.get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|location| Url::parse(&resp.url).ok()?.join(location).ok())
+                .map(|url| url.to_string())
+                .unwrap_or_default(),
+            false => String::new(),
+        },
+        Var::FilenameEffective => resp.saved.as_ref().map(|saved| saved.path.clone()).unwrap_or_default(),
+        Var::Digest => resp.digest.clone().unwrap_or_default(),
+        Var::ErrorMsg => String::new(),
+    }
+}
+
+// One result as a JSON record. Every field is always present (null when it
+// doesn't apply) so scripts can rely on the shape.
+#[derive(Serialize)]
+struct ResultRecord<'a> {
+    url: &'a str,
+    method: Option<&'a str>,
+    status: Option<u16>,
+    headers: Option<Map<String, Value>>,
+    // The body as text, or null when it's binary
+    body: Option<Cow<'a, str>>,
+    // A binary body, base64 encoded
+    body_base64: Option<String>,
+    content_length: Option<u64>,
+    latency_ms: Option<f64>,
+    redirects: Option<Vec<RedirectRecord<'a>>>,
+    // The file a download went to, in place of the body
+    saved_to: Option<&'a str>,
+    // ALGO:HEX, with --print-digest
+    digest: Option<&'a str>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RedirectRecord<'a> {
+    url: &'a str,
+    status: u16,
+    location: &'a str,
+}
+
+impl<'a> ResultRecord<'a> {
+    // `method` is what was asked for, which is all there is when the request failed
+    fn new(url: &'a str, method: Option<&'a str>, res: &'a anyhow::Result<HttpResult>) -> Self {
+        match res {
+            Ok(resp) => ResultRecord {
+                url,
+                method: Some(resp.method.as_str()),
+                status: Some(resp.status.as_u16()),
+                headers: Some(headers_to_map(&resp.headers)),
+                body: (!resp.is_binary()).then(|| resp.text()),
+                body_base64: resp.is_binary().then(|| STANDARD.encode(&resp.body)),
+                content_length: resp.content_length,
+                latency_ms: Some(resp.latency.as_secs_f64() * 1000.0),
+                redirects: Some(resp.redirects.iter()
+                    .map(|r| RedirectRecord { url: &r.url, status: r.status.as_u16(), location: &r.location })
+                    .collect()),
+                saved_to: resp.saved.as_ref().map(|saved| saved.path.as_str()),
+                digest: resp.digest.as_deref(),
+                error: None,
+            },
+            Err(e) => ResultRecord {
+                url,
+                method,
+                status: None,
+                headers: None,
+                body: None,
+                body_base64: None,
+                content_length: None,
+                latency_ms: None,
+                redirects: None,
+                saved_to: None,
+                digest: None,
+                error: Some(format!("{:#}", e)),
+            },
+        }
+    }
+}
+
+// Header names map to an array of their values, one per line sent, so a
+// repeated header (e.g. Set-Cookie) has the same shape as any other
+fn headers_to_map(headers: &reqwest::header::HeaderMap) -> Map<String, Value> {
+    let mut map = Map::new();
+
+    for name in headers.keys() {
+        let values: Vec<Value> = headers.get_all(name)
+            .iter()
+            .map(|v| Value::String(String::from_utf8_lossy(v.as_bytes()).into_owned()))
+            .collect();
+        map.insert(name.as_str().to_string(), Value::Array(values));
+    }
+
+    map
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &ResultRecord) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, record).map_err(io::Error::other)
+}
+
+// "-" is stdout too, and says binary bodies are wanted there
 pub fn build_writer(path: &Option<String>) -> io::Result<Box<dyn Write>> {
-    let writer: Box<dyn Write> = if let Some(path) = path {
+    let writer: Box<dyn Write> = if let Some(path) = path.as_deref().filter(|path| *path != "-") {
         Box::new(File::create(path)?) // use `?` to propagate errors
     } else {
         Box::new(io::stdout())        // directly box stdout
@@ -14,12 +302,38 @@ pub fn build_writer(path: &Option<String>) -> io::Result<Box<dyn Write>> {
 }
 
 // A function that takes any type implementing the Write trait
-fn write_result<W: Write>(writer: &mut W, http_result: &HttpResult, output_latency: bool) -> io::Result<()> {
+fn write_result<W: Write>(writer: &mut W, http_result: &HttpResult, options: &OutputOptions) -> io::Result<()> {
+    if !http_result.redirects.is_empty() {
+        writeln!(writer, "Redirects:")?;
+        for redirect in &http_result.redirects {
+            writeln!(writer, "  {} {} -> {}", redirect.status.as_u16(), redirect.url, redirect.location)?;
+        }
+    }
     writeln!(writer, "Status: {}", http_result.status)?;
     writeln!(writer, "Content-Length: {:?}", http_result.content_length)?;
     writeln!(writer, "Headers: {:#?}", http_result.headers)?;
-    writeln!(writer, "Body:\n{}", http_result.body)?;
-    if output_latency {
+    if let Some(saved) = &http_result.saved {
+        match (saved.resumed_from, saved.segments) {
+            (0, 1) => writeln!(writer, "Saved: {} ({} bytes)", saved.path, saved.size)?,
+            (0, segments) => writeln!(writer, "Saved: {} ({} bytes in {} segments)", saved.path, saved.size, segments)?,
+            (from, _) => writeln!(writer, "Saved: {} ({} bytes, resumed at {})", saved.path, saved.size, from)?,
+        }
+    } else if http_result.is_binary() && options.terminal {
+        eprintln!("Warning: Binary output can mess up your terminal. Use -o - to print it anyway, or --save FILE to save it.");
+        writeln!(writer, "Body: <{} bytes of binary data>", http_result.body.len())?;
+    } else if http_result.is_binary() {
+        writeln!(writer, "Body:")?;
+        writer.write_all(&http_result.body)?;
+        writeln!(writer)?;
+    } else if !options.raw && is_json(http_result) {
+        writeln!(writer, "Body:\n{}", pretty_json(&http_result.text()))?;
+    } else {
+        writeln!(writer, "Body:\n{}", http_result.text())?;
+    }
+    if let Some(digest) = &http_result.digest {
+        writeln!(writer, "Digest: {}", digest)?;
+    }
+    if options.latency {
         writeln!(writer, "Latency: {:?}", http_result.latency)?;
     }
 
@@ -28,31 +342,186 @@ fn write_result<W: Write>(writer: &mut W, http_result: &HttpResult, output_laten
     Ok(())
 }
 
-pub fn write_results<W: Write>(
-    urls: Vec<String>,
-    results: Vec<anyhow::Result<HttpResult>>,
-    mut writer: W,
-    latency: bool,
-) -> io::Result<bool> {
-    let mut had_failure = false;
+// Writes results one at a time as they arrive, so nothing has to be held
+// until the whole batch is done
+pub struct ResultWriter<'a, W: Write> {
+    writer: W,
+    options: &'a OutputOptions,
+    written: usize,
+    had_failure: bool,
+    checksum_failed: bool,
+}
+
+impl<'a, W: Write> ResultWriter<'a, W> {
+    pub fn new(mut writer: W, options: &'a OutputOptions) -> io::Result<Self> {
+        if is_json_array(options) {
+            write!(writer, "[")?;
+        }
+
+        Ok(ResultWriter { writer, options, written: 0, had_failure: false, checksum_failed: false })
+    }
+
+    pub fn write(&mut self, url: &str, res: anyhow::Result<HttpResult>) -> io::Result<()> {
+        self.write_with(url, None, None, res)
+    }
+
+    // Like write, for the result of a spec: a failed request still reports
+    // its method, and a response only counts as a success when it has the
+    // expected status, if one is given, rather than any 2xx
+    pub fn write_spec(&mut self, spec: &RequestSpec, res: anyhow::Result<HttpResult>) -> io::Result<()> {
+        self.write_with(&spec.url, Some(spec.method.as_str()), spec.expect_status, res)
+    }
+
+    fn write_with(
+        &mut self,
+        url: &str,
+        method: Option<&str>,
+        expect_status: Option<u16>,
+        res: anyhow::Result<HttpResult>,
+    ) -> io::Result<()> {
+        let writer = &mut self.writer;
+
+        if let Some(write_out) = &self.options.write_out {
+            write!(writer, "{}", write_out.render(url, &res))?;
+        } else {
+            match self.options.format {
+                OutputFormat::Human => {
+                    if let Ok(resp) = &res {
+                        write_result(writer, resp, self.options)?;
+                    }
+                }
+                OutputFormat::Json => {
+                    if self.written > 0 {
+                        write!(writer, ",")?;
+                    }
+                    writeln!(writer)?;
+                    write_record(writer, &ResultRecord::new(url, method, &res))?;
+                }
+                OutputFormat::Ndjson => {
+                    write_record(writer, &ResultRecord::new(url, method, &res))?;
+                    writeln!(writer)?;
+                }
+            }
+        }
+        writer.flush()?;
+        self.written += 1;
 
-    for (url, res) in urls.iter().zip(results) {
         match res {
-            Ok(resp) => {
-                write_result(&mut writer, &resp, latency)?;
-                if !resp.status.is_success() {
+            Ok(resp) => match expect_status {
+                Some(expected) if resp.status.as_u16() != expected => {
+                    eprintln!("Request to {} returned {}, expected {}", url, resp.status, expected);
+                    self.had_failure = true;
+                }
+                // A resume that finds the file already complete gets a 416
+                None if !resp.status.is_success() && resp.saved.is_none() => {
                     eprintln!("Request to {} returned {}", url, resp.status);
-                    had_failure = true;
+                    self.had_failure = true;
                 }
-            }
+                _ => {}
+            },
             Err(e) => {
                 eprintln!("Request to {} failed: {}", url, e);
-                had_failure = true;
+                self.had_failure = true;
+                self.checksum_failed |= e.is::<ChecksumMismatch>();
             }
         }
+
+        Ok(())
     }
 
-    Ok(had_failure)
+    // Whether a body didn't match its checksum
+    pub fn checksum_failed(&self) -> bool {
+        self.checksum_failed
+    }
+
+    // Close off the output, returning whether any request failed
+    pub fn finish(mut self) -> io::Result<bool> {
+        if is_json_array(self.options) {
+            writeln!(self.writer, "\n]")?;
+            self.writer.flush()?;
+        }
+
+        Ok(self.had_failure)
+    }
+}
+
+// A write-out template replaces the chosen format
+fn is_json_array(options: &OutputOptions) -> bool {
+    options.write_out.is_none() && options.format == OutputFormat::Json
+}
+
+// application/json or any +json type, ignoring parameters
+fn is_json(http_result: &HttpResult) -> bool {
+    media_type(&http_result.headers)
+        .is_some_and(|mime| mime == "application/json" || mime.ends_with("+json"))
+}
+
+// Re-indent a JSON document. This works on the text rather than a parsed
+// value so key order and number precision are kept exactly as sent.
+// Anything that isn't valid JSON is returned untouched.
+fn pretty_json(body: &str) -> String {
+    if serde_json::from_str::<serde::de::IgnoredAny>(body).is_err() {
+        return body.to_string();
+    }
+
+    let mut out = String::with_capacity(body.len() * 2);
+    let mut indent = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut chars = body.trim().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            }
+            '{' | '[' => {
+                out.push(c);
+                // Keep empty containers on one line
+                while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                    chars.next();
+                }
+                if matches!(chars.peek(), Some('}') | Some(']')) {
+                    out.push(chars.next().unwrap_or_default());
+                } else {
+                    indent += 1;
+                    newline(&mut out, indent);
+                }
+            }
+            '}' | ']' => {
+                indent -= 1;
+                newline(&mut out, indent);
+                out.push(c);
+            }
+            ',' => {
+                out.push(c);
+                newline(&mut out, indent);
+            }
+            ':' => out.push_str(": "),
+            c if c.is_whitespace() => {}
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+fn newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    out.push_str(&"  ".repeat(indent));
 }
 
 #[cfg(test)]
@@ -62,6 +531,9 @@ mod tests {
     use tempfile::tempdir;
     use std::fs;
     use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+    use crate::download::SavedFile;
+    use crate::redirect::Redirect;
+    use crate::cli::CliMethod;
 
     #[test]
     fn build_writer_returns_stdout_when_none() {
@@ -105,16 +577,35 @@ mod tests {
         );
 
         HttpResult {
+            method: reqwest::Method::GET,
+            url: "https://example.com/final".to_string(),
             status: reqwest::StatusCode::OK,
             content_length: Some(123),
             headers, // <-- now a real HeaderMap
-            body: r#"{"message":"hello"}"#.to_string(),
+            body: bytes::Bytes::from_static(br#"{"message":"hello"}"#),
+            time_to_first_byte: std::time::Duration::from_millis(40),
             latency: std::time::Duration::from_millis(42),
+            redirects: Vec::new(),
+            saved: None,
+            digest: None,
+        }
+    }
+
+    // Each result through a ResultWriter, returning whether any failed
+    fn write_each<W: Write>(urls: Vec<String>, results: Vec<anyhow::Result<HttpResult>>, writer: W, options: &OutputOptions) -> bool {
+        let mut result_writer = ResultWriter::new(writer, options).unwrap();
+        for (url, res) in urls.iter().zip(results) {
+            result_writer.write(url, res).unwrap();
         }
+        result_writer.finish().unwrap()
+    }
+
+    fn latency_options() -> OutputOptions {
+        OutputOptions { latency: true, ..Default::default() }
     }
 
     #[test]
-    fn test_write_results_all_success() {
+    fn test_result_writer_all_success() {
         // Arrange
         let urls = vec![
             "https://example.com/1".to_string(),
@@ -130,21 +621,21 @@ mod tests {
 
         // Act
         let had_failure =
-            write_results(urls, results, Box::new(&mut buffer), true).unwrap();
+            write_each(urls, results, Box::new(&mut buffer), &latency_options());
 
         let output = String::from_utf8(buffer).unwrap();
 
         // Assert
-        assert_eq!(had_failure, false); // all were successful
+        assert!(!had_failure); // all were successful
         assert!(output.contains("Status: 200 OK"));
         assert!(output.contains("Content-Length: Some(123)"));
         assert!(output.contains("application/json"));
-        assert!(output.contains(r#"{"message":"hello"}"#));
+        assert!(output.contains(r#""message": "hello""#));
         assert!(output.contains("Latency:")); // because latency flag is true
     }
 
     #[test]
-    fn test_write_results_with_failure() {
+    fn test_result_writer_with_failure() {
         // Arrange
         let urls = vec![
             "https://good.example.com".to_string(),
@@ -163,20 +654,20 @@ mod tests {
 
         // Act
         let had_failure =
-            write_results(urls, results, Box::new(&mut buffer), false).unwrap();
+            write_each(urls, results, Box::new(&mut buffer), &OutputOptions::default());
 
         let output = String::from_utf8(buffer).unwrap();
 
         // Assert
-        assert_eq!(had_failure, true); // at least one failure
+        assert!(had_failure); // at least one failure
         assert!(output.contains("Status: 200 OK"));
         assert!(output.contains("Status: 500 Internal Server Error"));
-        assert!(output.contains(r#"{"message":"hello"}"#));
+        assert!(output.contains(r#""message": "hello""#));
         assert!(!output.contains("Latency:")); // latency flag is false here
     }
 
     #[test]
-    fn test_write_results_with_err_variant() {
+    fn test_result_writer_with_err_variant() {
         // Arrange
         let urls = vec![
             "https://good.example.com".to_string(),
@@ -192,12 +683,12 @@ mod tests {
 
         // Act
         let had_failure =
-            write_results(urls, results, Box::new(&mut buffer), true).unwrap();
+            write_each(urls, results, Box::new(&mut buffer), &latency_options());
 
         let output = String::from_utf8(buffer).unwrap();
 
         // Assert
-        assert_eq!(had_failure, true); // because of the Err
+        assert!(had_failure); // because of the Err
         assert!(output.contains("Status: 200 OK")); // first result still written
     }
 
@@ -206,13 +697,15 @@ mod tests {
         let mut buffer: Vec<u8> = Vec::new();
         let http_result = sample_http_result();
 
-        write_result(&mut buffer, &http_result, false).unwrap();
+        write_result(&mut buffer, &http_result, &OutputOptions::default()).unwrap();
 
         let output = String::from_utf8(buffer).unwrap();
         assert!(output.contains("Status: 200 OK"));
         assert!(output.contains("Content-Length: Some(123)"));
         assert!(output.contains(r#"Body:
-{"message":"hello"}"#));
+{
+  "message": "hello"
+}"#));
         assert!(!output.contains("Latency:")); // should NOT include latency
     }
 
@@ -221,7 +714,7 @@ mod tests {
         let mut buffer: Vec<u8> = Vec::new();
         let http_result = sample_http_result();
 
-        write_result(&mut buffer, &http_result, true).unwrap();
+        write_result(&mut buffer, &http_result, &latency_options()).unwrap();
 
         let output = String::from_utf8(buffer).unwrap();
         assert!(output.contains("Status: 200 OK"));
@@ -234,7 +727,7 @@ mod tests {
         let http_result = sample_http_result();
 
         // If flush wasn't called, some data could be missing
-        write_result(&mut buffer, &http_result, false).unwrap();
+        write_result(&mut buffer, &http_result, &OutputOptions::default()).unwrap();
 
         assert!(!buffer.is_empty(), "Buffer should contain written data");
     }
@@ -245,7 +738,7 @@ mod tests {
         let http_result = sample_http_result();
 
         // Call write_result with latency enabled
-        write_result(&mut buffer, &http_result, true).unwrap();
+        write_result(&mut buffer, &http_result, &latency_options()).unwrap();
 
         // Convert buffer into a String
         let output = String::from_utf8(buffer).unwrap();
@@ -254,20 +747,449 @@ mod tests {
         assert!(output.contains("Status: 200 OK"));
         assert!(output.contains("Content-Length: Some(123)"));
         assert!(output.contains("application/json"));
-        assert!(output.contains(r#"{"message":"hello"}"#));
+        assert!(output.contains(r#""message": "hello""#));
         assert!(output.contains("Latency:")); // because we enabled output_latency
     }
 
+    fn redirected_http_result() -> HttpResult {
+        HttpResult {
+            redirects: vec![
+                Redirect {
+                    url: "http://example.com/start".to_string(),
+                    status: reqwest::StatusCode::MOVED_PERMANENTLY,
+                    location: "https://example.com/start".to_string(),
+                },
+                Redirect {
+                    url: "https://example.com/start".to_string(),
+                    status: reqwest::StatusCode::FOUND,
+                    location: "https://example.com/final".to_string(),
+                },
+            ],
+            ..sample_http_result()
+        }
+    }
+
+    #[test]
+    fn write_result_lists_redirects() {
+        let mut buffer = Vec::new();
+        write_result(&mut buffer, &redirected_http_result(), &OutputOptions::default()).unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.starts_with(
+            "Redirects:\n  301 http://example.com/start -> https://example.com/start\n  \
+             302 https://example.com/start -> https://example.com/final\nStatus: 200 OK\n"
+        ));
+    }
+
+    #[test]
+    fn json_record_lists_redirects() {
+        let mut buffer = Vec::new();
+        let options = OutputOptions { format: OutputFormat::Ndjson, ..Default::default() };
+        write_each(vec!["http://example.com/start".to_string()], vec![Ok(redirected_http_result())], &mut buffer, &options);
+
+        let record: Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(record["redirects"][1]["url"], "https://example.com/start");
+        assert_eq!(record["redirects"][1]["status"], 302);
+        assert_eq!(record["redirects"][1]["location"], "https://example.com/final");
+
+        let write_out = WriteOut::parse("%{num_redirects} %{redirect_url}").unwrap();
+        assert_eq!(write_out.render("http://example.com/start", &Ok(redirected_http_result())), "2 ");
+    }
+
+    #[test]
+    fn downloads_report_the_file() {
+        let result = HttpResult {
+            body: bytes::Bytes::new(),
+            saved: Some(SavedFile { path: "big.iso".to_string(), bytes: 600, size: 1000, resumed_from: 400, segments: 1 }),
+            ..sample_http_result()
+        };
+
+        let mut buffer = Vec::new();
+        write_result(&mut buffer, &result, &OutputOptions::default()).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("Saved: big.iso (1000 bytes, resumed at 400)\n"));
+        assert!(!output.contains("Body:"));
+
+        let write_out = WriteOut::parse("%{filename_effective} %{size_download}").unwrap();
+        assert_eq!(write_out.render("https://example.com/big.iso", &Ok(result)), "big.iso 600");
+    }
+
+    fn binary_http_result() -> HttpResult {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+        HttpResult { headers, body: bytes::Bytes::from_static(b"\x00\x01\xfe\xff"), ..sample_http_result() }
+    }
+
+    #[test]
+    fn binary_body_held_back_from_a_terminal() {
+        let mut buffer = Vec::new();
+        let options = OutputOptions { terminal: true, ..Default::default() };
+        write_result(&mut buffer, &binary_http_result(), &options).unwrap();
+        assert!(String::from_utf8(buffer).unwrap().contains("Body: <4 bytes of binary data>\n"));
+
+        let mut buffer = Vec::new();
+        write_result(&mut buffer, &binary_http_result(), &OutputOptions::default()).unwrap();
+        assert!(buffer.windows(6).any(|w| w == b"\n\x00\x01\xfe\xff\n"));
+    }
+
+    #[test]
+    fn json_record_base64_encodes_binary_bodies() {
+        let mut buffer = Vec::new();
+        let options = OutputOptions { format: OutputFormat::Ndjson, ..Default::default() };
+        write_each(vec!["https://example.com/blob".to_string()], vec![Ok(binary_http_result())], &mut buffer, &options);
+
+        let record: Value = serde_json::from_slice(&buffer).unwrap();
+        assert!(record["body"].is_null());
+        assert_eq!(record["body_base64"], "AAH+/w==");
+    }
+
+    #[test]
+    fn write_out_redirect_url_when_not_followed() {
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("/moved"));
+        let result = HttpResult { status: reqwest::StatusCode::FOUND, headers, ..sample_http_result() };
+
+        let write_out = WriteOut::parse("%{num_redirects} %{redirect_url}").unwrap();
+        assert_eq!(write_out.render("https://example.com/final", &Ok(result)), "0 https://example.com/moved");
+    }
+
     #[test]
     fn test_write_result_without_latency() {
         let mut buffer = Vec::new();
         let http_result = sample_http_result();
 
-        write_result(&mut buffer, &http_result, false).unwrap();
+        write_result(&mut buffer, &http_result, &OutputOptions::default()).unwrap();
 
         let output = String::from_utf8(buffer).unwrap();
 
         assert!(output.contains("Status: 200 OK"));
         assert!(!output.contains("Latency:")); // no latency printed
     }
+
+    #[test]
+    fn write_result_raw_keeps_json_as_sent() {
+        let mut buffer = Vec::new();
+        let http_result = sample_http_result();
+        let options = OutputOptions { raw: true, ..Default::default() };
+
+        write_result(&mut buffer, &http_result, &options).unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains(r#"Body:
+{"message":"hello"}"#));
+    }
+
+    #[test]
+    fn write_result_leaves_non_json_alone() {
+        let mut buffer = Vec::new();
+        let mut http_result = sample_http_result();
+        http_result.headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+
+        write_result(&mut buffer, &http_result, &OutputOptions::default()).unwrap();
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains(r#"{"message":"hello"}"#));
+    }
+
+    #[test]
+    fn is_json_matches_json_media_types() {
+        let mut http_result = sample_http_result();
+        assert!(is_json(&http_result));
+
+        http_result.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json; charset=utf-8"));
+        assert!(is_json(&http_result));
+
+        http_result.headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        assert!(!is_json(&http_result));
+
+        http_result.headers.remove(CONTENT_TYPE);
+        assert!(!is_json(&http_result));
+    }
+
+    #[test]
+    fn pretty_json_nested() {
+        let body = r#"{"a":[1,2,{"b":"x, y: {z}"}],"c":{},"d":[],"e":"quote \" inside"}"#;
+
+        assert_eq!(pretty_json(body), r#"{
+  "a": [
+    1,
+    2,
+    {
+      "b": "x, y: {z}"
+    }
+  ],
+  "c": {},
+  "d": [],
+  "e": "quote \" inside"
+}"#);
+    }
+
+    #[test]
+    fn pretty_json_keeps_order_and_precision() {
+        let body = r#"{"z":12345678901234567890123,"a":1.50}"#;
+
+        assert_eq!(pretty_json(body), "{\n  \"z\": 12345678901234567890123,\n  \"a\": 1.50\n}");
+    }
+
+    #[test]
+    fn pretty_json_leaves_invalid_json_alone() {
+        assert_eq!(pretty_json("{not json"), "{not json");
+    }
+
+    #[test]
+    fn test_result_writer_json_array() {
+        let urls = vec![
+            "https://good.example.com".to_string(),
+            "https://error.example.com".to_string(),
+        ];
+
+        let results = vec![
+            Ok(sample_http_result()),
+            Err(anyhow::anyhow!("Network error")),
+        ];
+
+        let mut buffer = Vec::new();
+        let options = OutputOptions { format: OutputFormat::Json, ..Default::default() };
+
+        let had_failure = write_each(urls, results, &mut buffer, &options);
+        assert!(had_failure);
+
+        let records: Value = serde_json::from_slice(&buffer).unwrap();
+        let records = records.as_array().unwrap();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(records[0]["url"], "https://good.example.com");
+        assert_eq!(records[0]["method"], "GET");
+        assert_eq!(records[0]["status"], 200);
+        assert_eq!(records[0]["headers"]["content-type"], serde_json::json!(["application/json"]));
+        assert_eq!(records[0]["body"], r#"{"message":"hello"}"#);
+        assert!(records[0]["body_base64"].is_null());
+        assert_eq!(records[0]["content_length"], 123);
+        assert_eq!(records[0]["latency_ms"], 42.0);
+        assert!(records[0]["error"].is_null());
+
+        assert_eq!(records[1]["url"], "https://error.example.com");
+        assert!(records[1]["status"].is_null());
+        assert_eq!(records[1]["error"], "Network error");
+    }
+
+    #[test]
+    fn test_result_writer_json_array_empty() {
+        let mut buffer = Vec::new();
+        let options = OutputOptions { format: OutputFormat::Json, ..Default::default() };
+
+        write_each(vec![], vec![], &mut buffer, &options);
+
+        let records: Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(records, Value::Array(vec![]));
+    }
+
+    #[test]
+    fn test_result_writer_ndjson_one_line_per_result() {
+        let urls = vec![
+            "https://example.com/1".to_string(),
+            "https://example.com/2".to_string(),
+        ];
+
+        let results = vec![
+            Ok(sample_http_result()),
+            Ok(sample_http_result()),
+        ];
+
+        let mut buffer = Vec::new();
+        let options = OutputOptions { format: OutputFormat::Ndjson, ..Default::default() };
+
+        write_each(urls, results, &mut buffer, &options);
+
+        let output = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+
+        let second: Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(second["url"], "https://example.com/2");
+        assert_eq!(second["status"], 200);
+    }
+
+    #[test]
+    fn headers_to_map_always_uses_arrays() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2"));
+
+        let map = headers_to_map(&headers);
+
+        assert_eq!(map["content-type"], serde_json::json!(["text/plain"]));
+        assert_eq!(map["set-cookie"], serde_json::json!(["a=1", "b=2"]));
+    }
+
+    #[test]
+    fn write_out_parses_variables_headers_and_escapes() {
+        let write_out = WriteOut::parse(r"%{http_code} 100%% %header{X-Id}\t\n").unwrap();
+
+        assert_eq!(write_out.segments, vec![
+            Segment::Var(Var::HttpCode),
+            Segment::Literal(" 100% ".to_string()),
+            Segment::Header("X-Id".to_string()),
+            Segment::Literal("\t\n".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn write_out_rejects_unknown_variables() {
+        let err = WriteOut::parse("%{http_cod}").unwrap_err();
+        assert!(err.contains("Unknown write-out variable `http_cod`"));
+
+        assert!(WriteOut::parse("%{http_code").is_err());
+    }
+
+    #[test]
+    fn write_out_renders_result() {
+        let write_out = WriteOut::parse(
+            "%{method} %{url} %{url_effective} %{http_code} %{content_type} %{content_length} \
+             %{size_download} %{num_headers} %{time_starttransfer} %{time_total} %header{content-type}\n"
+        ).unwrap();
+
+        let line = write_out.render("https://example.com", &Ok(sample_http_result()));
+
+        assert_eq!(
+            line,
+            "GET https://example.com https://example.com/final 200 application/json 123 \
+             19 1 0.040000 0.042000 application/json\n"
+        );
+    }
+
+    #[test]
+    fn write_out_renders_failure() {
+        let write_out = WriteOut::parse("%{http_code} %{url} %{time_total} [%{content_type}] %{errormsg}").unwrap();
+
+        let line = write_out.render("https://example.com", &Err(anyhow::anyhow!("Network error")));
+
+        assert_eq!(line, "000 https://example.com 0.000000 [] Network error");
+    }
+
+    #[test]
+    fn write_out_replaces_result_dump() {
+        let urls = vec![
+            "https://example.com/1".to_string(),
+            "https://example.com/2".to_string(),
+        ];
+
+        let results = vec![
+            Ok(sample_http_result()),
+            Err(anyhow::anyhow!("Network error")),
+        ];
+
+        let mut buffer = Vec::new();
+        let options = OutputOptions {
+            write_out: Some(WriteOut::parse("%{url} %{http_code}\n").unwrap()),
+            ..Default::default()
+        };
+
+        write_each(urls, results, &mut buffer, &options);
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert_eq!(output, "https://example.com/1 200\nhttps://example.com/2 000\n");
+    }
+
+    #[test]
+    fn result_writer_writes_each_result_immediately() {
+        let mut buffer = Vec::new();
+        let options = OutputOptions { format: OutputFormat::Ndjson, ..Default::default() };
+
+        {
+            let mut result_writer = ResultWriter::new(&mut buffer, &options).unwrap();
+            result_writer.write("https://example.com/1", Ok(sample_http_result())).unwrap();
+            result_writer.write("https://example.com/2", Err(anyhow::anyhow!("Network error"))).unwrap();
+            assert!(result_writer.finish().unwrap());
+        }
+
+        let output = String::from_utf8(buffer).unwrap();
+        assert_eq!(output.lines().count(), 2);
+    }
+
+    // A writer the test can look into while a ResultWriter owns it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn result_writer_json_array_is_valid_as_it_grows() {
+        let buffer = SharedBuffer::default();
+        let options = OutputOptions { format: OutputFormat::Json, ..Default::default() };
+        // What's been written so far, closed off the way finish would
+        let records_so_far = || {
+            let mut closed = buffer.0.borrow().clone();
+            closed.extend_from_slice(b"\n]");
+            serde_json::from_slice::<Value>(&closed).unwrap().as_array().unwrap().len()
+        };
+
+        let mut result_writer = ResultWriter::new(buffer.clone(), &options).unwrap();
+        assert_eq!(records_so_far(), 0);
+        result_writer.write("https://example.com/1", Ok(sample_http_result())).unwrap();
+        assert_eq!(records_so_far(), 1);
+        result_writer.write("https://example.com/2", Ok(sample_http_result())).unwrap();
+        assert_eq!(records_so_far(), 2);
+        assert!(!result_writer.finish().unwrap());
+
+        let records: Value = serde_json::from_slice(&buffer.0.borrow()).unwrap();
+        assert_eq!(records.as_array().unwrap().len(), 2);
+    }
+
+    fn spec(method: CliMethod, expect_status: Option<u16>) -> RequestSpec {
+        RequestSpec {
+            url: "https://example.com/1".to_string(),
+            method,
+            headers: Vec::new(),
+            body: None,
+            auth: None,
+            expect_status,
+            download: None,
+            digests: Default::default(),
+            invalid: None,
+        }
+    }
+
+    #[test]
+    fn result_writer_checks_expected_status() {
+        let options = OutputOptions { format: OutputFormat::Ndjson, ..Default::default() };
+
+        let mut result_writer = ResultWriter::new(Vec::new(), &options).unwrap();
+        result_writer.write_spec(&spec(CliMethod::Get, Some(200)), Ok(sample_http_result())).unwrap();
+        assert!(!result_writer.finish().unwrap());
+
+        let mut result_writer = ResultWriter::new(Vec::new(), &options).unwrap();
+        result_writer.write_spec(&spec(CliMethod::Get, Some(201)), Ok(sample_http_result())).unwrap();
+        assert!(result_writer.finish().unwrap());
+
+        let mut not_found = sample_http_result();
+        not_found.status = reqwest::StatusCode::NOT_FOUND;
+        let mut result_writer = ResultWriter::new(Vec::new(), &options).unwrap();
+        result_writer.write_spec(&spec(CliMethod::Get, Some(404)), Ok(not_found)).unwrap();
+        assert!(!result_writer.finish().unwrap());
+    }
+
+    #[test]
+    fn result_writer_keeps_the_method_of_a_failed_spec() {
+        let mut buffer = Vec::new();
+        let options = OutputOptions { format: OutputFormat::Ndjson, ..Default::default() };
+
+        let mut result_writer = ResultWriter::new(&mut buffer, &options).unwrap();
+        result_writer.write_spec(&spec(CliMethod::Delete, None), Err(anyhow::anyhow!("Network error"))).unwrap();
+        assert!(result_writer.finish().unwrap());
+
+        let record: Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(record["method"], "DELETE");
+        assert!(record["status"].is_null());
+        assert_eq!(record["error"], "Network error");
+    }
 }
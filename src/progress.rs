@@ -0,0 +1,131 @@
+use std::io::{self, IsTerminal};
+use std::time::Duration;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+const BAR: &str = "{prefix} {wide_msg} {bytes}/{total_bytes} [{bar:20}] {bytes_per_sec} ETA {eta}";
+const NO_TOTAL: &str = "{prefix} {wide_msg} {bytes} {bytes_per_sec} {spinner}";
+
+// Progress on stderr for the transfers in flight, a line for each URL.
+// Hidden unless stderr is a terminal.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    bars: Option<MultiProgress>,
+}
+
+// Which way a body is going: > for an upload, < for a download, like -v
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+// One URL's line. Clones share it.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    bar: ProgressBar,
+    bars: Option<MultiProgress>,
+}
+
+impl Progress {
+    // Off with --silent, and when stderr is redirected
+    pub fn new(silent: bool) -> Self {
+        let shown = !silent && io::stderr().is_terminal();
+        Progress { bars: shown.then(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr())) }
+    }
+
+    // Add a line for a URL until the transfer's finished
+    pub fn start(&self, url: &str) -> Transfer {
+        let Some(bars) = &self.bars else {
+            return Transfer::hidden();
+        };
+
+        let bar = bars.add(ProgressBar::no_length());
+        bar.set_style(style(NO_TOTAL));
+        bar.set_message(url.to_string());
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Transfer { bar, bars: Some(bars.clone()) }
+    }
+
+    // Run f with the lines cleared, for writing to the terminal
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.bars {
+            Some(bars) => bars.suspend(f),
+            None => f(),
+        }
+    }
+}
+
+impl Transfer {
+    // Counts bytes without showing them
+    pub fn hidden() -> Self {
+        Transfer { bar: ProgressBar::hidden(), bars: None }
+    }
+
+    // Start counting a body. The total comes from Content-Length, and a
+    // resumed download starts part way.
+    pub fn begin(&self, direction: Direction, from: u64, total: Option<u64>) {
+        self.bar.set_prefix(match direction {
+            Direction::Upload => ">",
+            Direction::Download => "<",
+        });
+        match total {
+            Some(total) => {
+                self.bar.set_style(style(BAR));
+                self.bar.set_length(total);
+            }
+            None => {
+                self.bar.set_style(style(NO_TOTAL));
+                self.bar.unset_length();
+            }
+        }
+        self.bar.set_position(from);
+        self.bar.reset_eta();
+    }
+
+    pub fn advance(&self, bytes: usize) {
+        self.bar.inc(bytes as u64);
+    }
+
+    // Bytes counted since begin, including where it started
+    #[cfg(test)]
+    pub(crate) fn position(&self) -> u64 {
+        self.bar.position()
+    }
+
+    // Take the line away
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+        if let Some(bars) = &self.bars {
+            bars.remove(&self.bar);
+        }
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("progress template is valid")
+        .progress_chars("=> ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_transfers_still_count() {
+        let transfer = Progress::default().start("https://example.com/big.iso");
+
+        transfer.begin(Direction::Download, 400, Some(1000));
+        transfer.advance(250);
+        transfer.advance(350);
+
+        assert_eq!(transfer.position(), 1000);
+    }
+
+    #[test]
+    fn templates_parse() {
+        style(BAR);
+        style(NO_TOTAL);
+    }
+}
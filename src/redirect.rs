@@ -0,0 +1,272 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use log::{info, warn};
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, LOCATION, TRANSFER_ENCODING};
+use reqwest::{Method, Request, Response, StatusCode};
+use reqwest_middleware::{Middleware, Next};
+use task_local_extensions::Extensions;
+use url::Url;
+
+const MAX_REDIRS: u32 = 50;
+
+// Whether and how redirects are followed. They aren't unless asked, like curl.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedirectOptions {
+    pub follow: bool,
+    pub max_redirs: u32,
+    // Keep POST as POST on a 301, 302 or 303 instead of switching to GET
+    pub post301: bool,
+    pub post302: bool,
+    pub post303: bool,
+    // Send Authorization and Cookie headers to other hosts too
+    pub location_trusted: bool,
+}
+
+impl Default for RedirectOptions {
+    fn default() -> Self {
+        RedirectOptions {
+            follow: false,
+            max_redirs: MAX_REDIRS,
+            post301: false,
+            post302: false,
+            post303: false,
+            location_trusted: false,
+        }
+    }
+}
+
+// One hop: the URL asked for, the redirect status and where it pointed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub url: String,
+    pub status: StatusCode,
+    pub location: String,
+}
+
+// The hops taken, attached to the final response
+#[derive(Debug, Clone, Default)]
+pub struct RedirectChain(pub Vec<Redirect>);
+
+// Follows redirects itself (the client's own following is off) so each hop
+// is recorded and the method and credentials are handled the way curl does
+pub struct RedirectMiddleware {
+    options: RedirectOptions,
+}
+
+impl RedirectMiddleware {
+    pub fn new(options: RedirectOptions) -> Self {
+        RedirectMiddleware { options }
+    }
+
+    // The request for the next hop, from a copy of the last one
+    fn next_request(&self, mut req: Request, status: StatusCode, target: Url) -> Request {
+        let is_post = req.method() == Method::POST;
+        let switch_to_get = match status {
+            StatusCode::MOVED_PERMANENTLY => is_post && !self.options.post301,
+            StatusCode::FOUND => is_post && !self.options.post302,
+            // Anything but HEAD, unless it's a POST to keep
+            StatusCode::SEE_OTHER => req.method() != Method::HEAD && !(is_post && self.options.post303),
+            _ => false,
+        };
+
+        if switch_to_get {
+            *req.method_mut() = Method::GET;
+            *req.body_mut() = None;
+            for header in [CONTENT_TYPE, CONTENT_LENGTH, CONTENT_ENCODING, TRANSFER_ENCODING] {
+                req.headers_mut().remove(header);
+            }
+        }
+
+        let same_host = req.url().host_str() == target.host_str()
+            && req.url().port_or_known_default() == target.port_or_known_default();
+        if !same_host && !self.options.location_trusted {
+            info!("Redirect: leaving credentials behind for {}", target);
+            req.headers_mut().remove(AUTHORIZATION);
+            req.headers_mut().remove(COOKIE);
+        }
+
+        *req.url_mut() = target;
+        req
+    }
+}
+
+#[async_trait]
+impl Middleware for RedirectMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let mut chain = Vec::new();
+
+        loop {
+            // A streamed body can't be sent twice, so that response is the last
+            let replay = req.try_clone();
+            let mut resp = next.clone().run(req, extensions).await?;
+
+            let Some(location) = location(&resp) else {
+                resp.extensions_mut().insert(RedirectChain(chain));
+                return Ok(resp);
+            };
+            if chain.len() as u32 >= self.options.max_redirs {
+                return Err(anyhow!("Maximum ({}) redirects followed", self.options.max_redirs).into());
+            }
+            let Some(replay) = replay else {
+                warn!("Not following the redirect from {}: the body can't be sent again", resp.url());
+                resp.extensions_mut().insert(RedirectChain(chain));
+                return Ok(resp);
+            };
+
+            let target = resp.url().join(&location)
+                .map_err(|e| anyhow!("Invalid redirect location {}: {}", location, e))?;
+            if !matches!(target.scheme(), "http" | "https") {
+                return Err(anyhow!("Not following the redirect to {}: only http and https are", target).into());
+            }
+
+            info!("Redirect: {} {} -> {}", resp.status(), resp.url(), target);
+            chain.push(Redirect { url: resp.url().to_string(), status: resp.status(), location: target.to_string() });
+            req = self.next_request(replay, resp.status(), target);
+        }
+    }
+}
+
+// Where a redirect response points
+fn location(resp: &Response) -> Option<String> {
+    let redirects = [
+        StatusCode::MOVED_PERMANENTLY,
+        StatusCode::FOUND,
+        StatusCode::SEE_OTHER,
+        StatusCode::TEMPORARY_REDIRECT,
+        StatusCode::PERMANENT_REDIRECT,
+    ];
+    if !redirects.contains(&resp.status()) {
+        return None;
+    }
+    resp.headers().get(LOCATION)?.to_str().ok().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+    use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+
+    fn client(options: RedirectOptions) -> ClientWithMiddleware {
+        let base = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+        ClientBuilder::new(base).with(RedirectMiddleware::new(RedirectOptions { follow: true, ..options })).build()
+    }
+
+    fn chain(resp: &Response) -> Vec<(u16, String)> {
+        resp.extensions().get::<RedirectChain>().unwrap().0.iter()
+            .map(|r| (r.status.as_u16(), r.location.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn records_every_hop() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.path("/a");
+            then.status(301).header("Location", "/b");
+        }).await;
+        server.mock_async(|when, then| {
+            when.path("/b");
+            then.status(302).header("Location", "c");
+        }).await;
+        server.mock_async(|when, then| {
+            when.path("/c");
+            then.status(200).body("done");
+        }).await;
+
+        let resp = client(RedirectOptions::default()).get(server.url("/a")).send().await.unwrap();
+
+        assert_eq!(chain(&resp), vec![(301, server.url("/b")), (302, server.url("/c"))]);
+        assert_eq!(resp.url().as_str(), server.url("/c"));
+        assert_eq!(resp.text().await.unwrap(), "done");
+    }
+
+    #[tokio::test]
+    async fn stops_at_max_redirs() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.path("/loop");
+            then.status(302).header("Location", "/loop");
+        }).await;
+
+        let options = RedirectOptions { max_redirs: 3, ..Default::default() };
+        let e = client(options).get(server.url("/loop")).send().await.unwrap_err();
+
+        assert!(e.to_string().ends_with("Maximum (3) redirects followed"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn post_becomes_get_unless_kept() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.method(POST).path("/form");
+            then.status(302).header("Location", "/result");
+        }).await;
+        let get = server.mock_async(|when, then| {
+            when.method(GET).path("/result").matches(|req| !req.headers.iter().flatten().any(|(k, _)| k == "content-type"));
+            then.status(200);
+        }).await;
+        let post = server.mock_async(|when, then| {
+            when.method(POST).path("/result").body("a=1");
+            then.status(201);
+        }).await;
+
+        let send = |options| client(options).post(server.url("/form"))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("a=1")
+            .send();
+
+        assert_eq!(send(RedirectOptions::default()).await.unwrap().status(), 200);
+        assert_eq!(send(RedirectOptions { post302: true, ..Default::default() }).await.unwrap().status(), 201);
+        get.assert_async().await;
+        post.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn temporary_redirect_keeps_method_and_body() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.method(PUT).path("/old");
+            then.status(307).header("Location", "/new");
+        }).await;
+        let new = server.mock_async(|when, then| {
+            when.method(PUT).path("/new").body("payload");
+            then.status(204);
+        }).await;
+
+        let resp = client(RedirectOptions::default()).put(server.url("/old")).body("payload").send().await.unwrap();
+
+        assert_eq!(resp.status(), 204);
+        new.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn credentials_stay_on_the_first_host() {
+        let first = MockServer::start_async().await;
+        let other = MockServer::start_async().await;
+        first.mock_async(|when, then| {
+            when.path("/start");
+            then.status(302).header("Location", other.url("/landing"));
+        }).await;
+        let without = other.mock_async(|when, then| {
+            when.path("/landing").matches(|req| !req.headers.iter().flatten().any(|(k, _)| k == "authorization"));
+            then.status(200);
+        }).await;
+        let with = other.mock_async(|when, then| {
+            when.path("/landing").header("authorization", "Bearer abc");
+            then.status(202);
+        }).await;
+
+        let send = |options| client(options).get(first.url("/start")).header("Authorization", "Bearer abc").send();
+
+        assert_eq!(send(RedirectOptions::default()).await.unwrap().status(), 200);
+        assert_eq!(send(RedirectOptions { location_trusted: true, ..Default::default() }).await.unwrap().status(), 202);
+        without.assert_async().await;
+        with.assert_async().await;
+    }
+}
@@ -0,0 +1,473 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use reqwest::Method;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::body::RequestBody;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+// Where requests are signed for, from --aws-sigv4 provider:region:service
+#[derive(Debug, Clone, PartialEq)]
+pub struct AwsScope {
+    pub region: String,
+    pub service: String,
+}
+
+impl FromStr for AwsScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            [provider, region, service] if !region.is_empty() && !service.is_empty() => {
+                if !provider.eq_ignore_ascii_case("aws") {
+                    return Err(format!("unsupported SigV4 provider `{}`: only aws is supported", provider));
+                }
+                Ok(AwsScope { region: region.to_string(), service: service.to_string() })
+            }
+            _ => Err(format!("invalid SigV4 scope `{}`: expected provider:region:service", s)),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+// Never print the secrets, even in debug logs
+impl fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AwsCredentials({}:***)", self.access_key_id)
+    }
+}
+
+impl AwsCredentials {
+    // AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY (and AWS_SESSION_TOKEN), or
+    // else the AWS_PROFILE (or default) profile in the shared credentials file
+    pub fn load() -> Result<AwsCredentials> {
+        if let (Ok(access_key_id), Ok(secret_access_key)) = (std::env::var("AWS_ACCESS_KEY_ID"), std::env::var("AWS_SECRET_ACCESS_KEY")) {
+            return Ok(AwsCredentials {
+                access_key_id,
+                secret_access_key,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            });
+        }
+
+        let path = credentials_path().context("Can't find AWS credentials: set AWS_ACCESS_KEY_ID or HOME")?;
+        let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Can't find AWS credentials in the environment or {}", path.display()))?;
+
+        AwsCredentials::from_credentials_file(&contents, &profile)
+            .with_context(|| format!("No AWS credentials for profile {} in {}", profile, path.display()))
+    }
+
+    // Read a profile from the INI style shared credentials file
+    fn from_credentials_file(contents: &str, profile: &str) -> Option<AwsCredentials> {
+        let mut in_profile = false;
+        let mut access_key_id = None;
+        let mut secret_access_key = None;
+        let mut session_token = None;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                in_profile = section.trim() == profile;
+                continue;
+            }
+            if !in_profile {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                let value = Some(value.trim().to_string());
+                match key.trim() {
+                    "aws_access_key_id" => access_key_id = value,
+                    "aws_secret_access_key" => secret_access_key = value,
+                    "aws_session_token" => session_token = value,
+                    _ => {}
+                }
+            }
+        }
+
+        Some(AwsCredentials { access_key_id: access_key_id?, secret_access_key: secret_access_key?, session_token })
+    }
+}
+
+fn credentials_path() -> Option<PathBuf> {
+    std::env::var_os("AWS_SHARED_CREDENTIALS_FILE")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".aws").join("credentials")))
+}
+
+// Signs requests with AWS Signature Version 4
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigV4 {
+    pub scope: AwsScope,
+    pub credentials: AwsCredentials,
+}
+
+impl SigV4 {
+    // The headers to add to the request: the date, the payload hash for S3,
+    // the session token if any, and the Authorization header that signs them
+    // along with the method, URL, the given headers and the payload.
+    pub async fn sign(
+        &self,
+        method: &Method,
+        url: &str,
+        headers: &[(String, String)],
+        body: Option<&RequestBody>,
+    ) -> Result<Vec<(String, String)>> {
+        let payload_hash = payload_hash(body).await?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.sign_at(method, url, headers, &payload_hash, now)
+    }
+
+    fn sign_at(
+        &self,
+        method: &Method,
+        url: &str,
+        headers: &[(String, String)],
+        payload_hash: &str,
+        now: u64,
+    ) -> Result<Vec<(String, String)>> {
+        let url = url::Url::parse(url)?;
+        let amz_date = amz_date(now);
+        let date = &amz_date[..8];
+        let is_s3 = self.scope.service == "s3";
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let mut added = vec![("X-Amz-Date".to_string(), amz_date.clone())];
+        if is_s3 {
+            added.push(("X-Amz-Content-Sha256".to_string(), payload_hash.to_string()));
+        }
+        if let Some(token) = &self.credentials.session_token {
+            added.push(("X-Amz-Security-Token".to_string(), token.clone()));
+        }
+
+        // Every header that goes out, lower cased and sorted, with repeated
+        // headers joined by commas
+        let mut canonical: Vec<(String, String)> = vec![("host".to_string(), host)];
+        for (key, value) in headers.iter().chain(&added) {
+            let key = key.to_ascii_lowercase();
+            if key == "host" || key == "authorization" {
+                continue;
+            }
+            let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+            match canonical.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, existing)) => {
+                    existing.push(',');
+                    existing.push_str(&value);
+                }
+                None => canonical.push((key, value)),
+            }
+        }
+        canonical.sort();
+
+        let signed_headers = canonical.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>().join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            canonical_uri(url.path(), is_s3),
+            canonical_query(url.query().unwrap_or("")),
+            canonical.iter().map(|(k, v)| format!("{}:{}\n", k, v)).collect::<String>(),
+            signed_headers,
+            payload_hash,
+        );
+
+        let credential_scope = format!("{}/{}/{}/aws4_request", date, self.scope.region, self.scope.service);
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{:x}",
+            ALGORITHM, amz_date, credential_scope, Sha256::digest(&canonical_request),
+        );
+
+        let mut key = hmac(format!("AWS4{}", self.credentials.secret_access_key).as_bytes(), date);
+        for part in [&self.scope.region, &self.scope.service, "aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let signature = hex(&hmac(&key, &string_to_sign));
+
+        added.push((
+            "Authorization".to_string(),
+            format!(
+                "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+                ALGORITHM, self.credentials.access_key_id, credential_scope, signed_headers, signature
+            ),
+        ));
+        Ok(added)
+    }
+}
+
+// The SHA-256 of the body, reading a streamed file in chunks
+async fn payload_hash(body: Option<&RequestBody>) -> Result<String> {
+    let mut hasher = Sha256::new();
+    match body {
+        None => {}
+        Some(RequestBody::Bytes(data)) => hasher.update(data),
+        Some(RequestBody::File { path, .. }) => {
+            let mut file = tokio::fs::File::open(path).await?;
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+            }
+        }
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+// Each path segment decoded and encoded again the AWS way. Services other
+// than S3 expect the already encoded path to be encoded a second time.
+fn canonical_uri(path: &str, is_s3: bool) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.split('/')
+        .map(|segment| {
+            let once = encode(&percent_decode(segment));
+            if is_s3 { once } else { encode(once.as_bytes()) }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Parameters decoded, encoded the AWS way and sorted
+fn canonical_query(query: &str) -> String {
+    let mut params: Vec<(String, String)> = query.split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (encode(&percent_decode(key)), encode(&percent_decode(value)))
+        })
+        .collect();
+    params.sort();
+    params.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&")
+}
+
+// Percent-encode everything but the RFC 3986 unreserved characters
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    percent_encoding::percent_decode_str(s).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// YYYYMMDDTHHMMSSZ for a Unix time
+fn amz_date(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The date and credentials of the AWS SigV4 test suite
+    const SUITE_TIME: u64 = 1_440_938_160; // 20150830T123600Z
+
+    fn suite_signer(session_token: Option<&str>) -> SigV4 {
+        SigV4 {
+            scope: AwsScope { region: "us-east-1".to_string(), service: "service".to_string() },
+            credentials: AwsCredentials {
+                access_key_id: "AKIDEXAMPLE".to_string(),
+                secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+                session_token: session_token.map(str::to_string),
+            },
+        }
+    }
+
+    fn suite_signature(method: Method, path: &str, headers: &[(&str, &str)], body: &str) -> String {
+        let headers: Vec<(String, String)> = headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let payload_hash = format!("{:x}", Sha256::digest(body));
+        let url = format!("https://example.amazonaws.com{}", path);
+
+        let signed = suite_signer(None).sign_at(&method, &url, &headers, &payload_hash, SUITE_TIME).unwrap();
+
+        let authorization = &signed.last().unwrap().1;
+        authorization.rsplit("Signature=").next().unwrap().to_string()
+    }
+
+    #[test]
+    fn suite_get_vanilla() {
+        let signer = suite_signer(None);
+        let payload_hash = format!("{:x}", Sha256::digest(""));
+
+        let signed = signer.sign_at(&Method::GET, "https://example.amazonaws.com/", &[], &payload_hash, SUITE_TIME).unwrap();
+
+        assert_eq!(signed, vec![
+            ("X-Amz-Date".to_string(), "20150830T123600Z".to_string()),
+            ("Authorization".to_string(), concat!(
+                "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, ",
+                "SignedHeaders=host;x-amz-date, ",
+                "Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            ).to_string()),
+        ]);
+    }
+
+    #[test]
+    fn suite_post_vanilla() {
+        assert_eq!(
+            suite_signature(Method::POST, "/", &[], ""),
+            "5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+        );
+    }
+
+    #[test]
+    fn suite_get_vanilla_query_order_key_case() {
+        assert_eq!(
+            suite_signature(Method::GET, "/?Param2=value2&Param1=value1", &[], ""),
+            "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+    }
+
+    #[test]
+    fn suite_get_unreserved() {
+        assert_eq!(
+            suite_signature(Method::GET, "/-._~0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz", &[], ""),
+            "07ef7494c76fa4850883e2b006601f940f8a34d404d0cfa977f52a65bbf5f24f"
+        );
+    }
+
+    #[test]
+    fn suite_post_x_www_form_urlencoded() {
+        assert_eq!(
+            suite_signature(Method::POST, "/", &[("Content-Type", "application/x-www-form-urlencoded")], "Param1=value1"),
+            "ff11897932ad3f4e8b18135d722051e5ac45fc38421b1da7b9d196a0fe09473a"
+        );
+    }
+
+    #[test]
+    fn suite_get_header_value_trim() {
+        assert_eq!(
+            suite_signature(Method::GET, "/", &[("My-Header1", " value1"), ("My-Header2", " \"a   b   c\"")], ""),
+            "acc3ed3afb60bb290fc8d2dd0098b9911fcaa05412b367055dee359757a9c736"
+        );
+    }
+
+    #[test]
+    fn session_token_is_signed() {
+        let payload_hash = format!("{:x}", Sha256::digest(""));
+
+        let signed = suite_signer(Some("token")).sign_at(&Method::GET, "https://example.amazonaws.com/", &[], &payload_hash, SUITE_TIME).unwrap();
+
+        assert_eq!(signed[1], ("X-Amz-Security-Token".to_string(), "token".to_string()));
+        assert!(signed[2].1.contains("SignedHeaders=host;x-amz-date;x-amz-security-token,"));
+    }
+
+    #[test]
+    fn s3_signs_payload_hash() {
+        let signer = SigV4 { scope: AwsScope { region: "us-east-1".to_string(), service: "s3".to_string() }, ..suite_signer(None) };
+
+        let signed = signer.sign_at(&Method::PUT, "http://localhost:9000/bucket/key", &[], "UNSIGNED", SUITE_TIME).unwrap();
+
+        assert_eq!(signed[1], ("X-Amz-Content-Sha256".to_string(), "UNSIGNED".to_string()));
+        assert!(signed[2].1.contains("SignedHeaders=host;x-amz-content-sha256;x-amz-date,"));
+    }
+
+    #[test]
+    fn canonical_uri_encoding() {
+        assert_eq!(canonical_uri("", false), "/");
+        assert_eq!(canonical_uri("/a%20b/c", true), "/a%20b/c");
+        // Everything but S3 encodes the encoded path again
+        assert_eq!(canonical_uri("/a%20b/c", false), "/a%2520b/c");
+        assert_eq!(canonical_uri("/key=v&x", true), "/key%3Dv%26x");
+    }
+
+    #[test]
+    fn canonical_query_sorts_and_encodes() {
+        assert_eq!(canonical_query("b=2&a=x%20y&c"), "a=x%20y&b=2&c=");
+        assert_eq!(canonical_query(""), "");
+    }
+
+    #[test]
+    fn amz_date_formats_utc() {
+        assert_eq!(amz_date(SUITE_TIME), "20150830T123600Z");
+        assert_eq!(amz_date(0), "19700101T000000Z");
+        assert_eq!(amz_date(951_782_400), "20000229T000000Z");
+    }
+
+    #[test]
+    fn scope_parsing() {
+        assert_eq!(
+            "aws:eu-west-1:execute-api".parse::<AwsScope>().unwrap(),
+            AwsScope { region: "eu-west-1".to_string(), service: "execute-api".to_string() }
+        );
+        assert!("aws:eu-west-1".parse::<AwsScope>().is_err());
+        assert!("gcp:eu-west-1:storage".parse::<AwsScope>().is_err());
+    }
+
+    #[test]
+    fn credentials_file_profiles() {
+        let contents = "\
+[default]
+aws_access_key_id = AKIDDEFAULT
+aws_secret_access_key = default-secret
+
+; MinIO running locally
+[minio]
+aws_access_key_id=minioadmin
+aws_secret_access_key=minioadmin
+aws_session_token = tok
+";
+
+        let default = AwsCredentials::from_credentials_file(contents, "default").unwrap();
+        assert_eq!(default.access_key_id, "AKIDDEFAULT");
+        assert_eq!(default.session_token, None);
+
+        let minio = AwsCredentials::from_credentials_file(contents, "minio").unwrap();
+        assert_eq!(minio.secret_access_key, "minioadmin");
+        assert_eq!(minio.session_token.as_deref(), Some("tok"));
+
+        assert!(AwsCredentials::from_credentials_file(contents, "missing").is_none());
+    }
+}
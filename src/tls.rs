@@ -0,0 +1,565 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+
+use crate::cli::Cli;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The oldest TLS version to accept. rustls only speaks TLS 1.2 and 1.3.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+pub fn parse_tls_version(s: &str) -> Result<TlsVersion, String> {
+    match s {
+        "1.2" => Ok(TlsVersion::Tls12),
+        "1.3" => Ok(TlsVersion::Tls13),
+        "1.0" | "1.1" => Err(format!("TLS {} isn't supported: the oldest is 1.2", s)),
+        _ => Err(format!("invalid TLS version `{}`: expected 1.2 or 1.3", s)),
+    }
+}
+
+// How to verify servers and identify ourselves to them
+#[derive(Clone, Default, PartialEq)]
+pub struct TlsOptions {
+    // Trust only the CAs in this PEM file and/or the PEM files in this directory
+    pub cacert: Option<PathBuf>,
+    pub capath: Option<PathBuf>,
+    // Client certificate: a PEM chain, or a PKCS#12 (.p12/.pfx) bundle
+    pub cert: Option<PathBuf>,
+    // The PKCS#12 password
+    pub cert_password: Option<String>,
+    // PEM private key for a PEM cert, when it isn't in the cert file
+    pub key: Option<PathBuf>,
+    // Skip verifying the server's certificate
+    pub insecure: bool,
+    pub min_version: Option<TlsVersion>,
+    // SHA-256 hashes of the public keys the server may have
+    pub pinned_pubkeys: Vec<[u8; 32]>,
+}
+
+// Keep the PKCS#12 password out of logs
+impl std::fmt::Debug for TlsOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsOptions")
+            .field("cacert", &self.cacert)
+            .field("capath", &self.capath)
+            .field("cert", &self.cert)
+            .field("cert_password", &self.cert_password.as_ref().map(|_| "<redacted>"))
+            .field("key", &self.key)
+            .field("insecure", &self.insecure)
+            .field("min_version", &self.min_version)
+            .field("pinned_pubkeys", &self.pinned_pubkeys.len())
+            .finish()
+    }
+}
+
+impl TlsOptions {
+    pub fn from_cli(cli: &Cli) -> Result<TlsOptions> {
+        // --cert file:password, unless the whole argument names a file
+        let (cert, cert_password) = match &cli.cert {
+            Some(arg) if !Path::new(arg).exists() && let Some((path, password)) = arg.split_once(':') => {
+                (Some(PathBuf::from(path)), Some(password.to_string()))
+            }
+            Some(arg) => (Some(PathBuf::from(arg)), None),
+            None => (None, None),
+        };
+
+        Ok(TlsOptions {
+            cacert: cli.cacert.as_ref().map(PathBuf::from),
+            capath: cli.capath.as_ref().map(PathBuf::from),
+            cert,
+            cert_password,
+            key: cli.key.as_ref().map(PathBuf::from),
+            insecure: cli.insecure,
+            min_version: cli.tls_min_version,
+            pinned_pubkeys: match &cli.pinnedpubkey {
+                Some(arg) => parse_pinned_pubkeys(arg)?,
+                None => Vec::new(),
+            },
+        })
+    }
+
+    // Whether the client needs a TLS configuration of its own
+    pub fn is_default(&self) -> bool {
+        *self == TlsOptions::default()
+    }
+}
+
+// The rustls configuration for the options
+pub fn client_config(options: &TlsOptions) -> Result<ClientConfig> {
+    build_config(options, None)
+}
+
+// Like client_config, also keeping every certificate chain the server sends
+// in `seen`, even ones that fail verification
+fn build_config(options: &TlsOptions, seen: Option<Arc<Mutex<Vec<Certificate>>>>) -> Result<ClientConfig> {
+    let versions: &[&rustls::SupportedProtocolVersion] = match options.min_version {
+        Some(TlsVersion::Tls13) => &[&rustls::version::TLS13],
+        _ => &[&rustls::version::TLS12, &rustls::version::TLS13],
+    };
+
+    let verifier = PinningVerifier {
+        inner: match options.insecure {
+            true => None,
+            false => Some(WebPkiVerifier::new(root_store(options)?, None)),
+        },
+        pins: options.pinned_pubkeys.clone(),
+        seen,
+    };
+
+    let builder = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)?
+        .with_custom_certificate_verifier(Arc::new(verifier));
+
+    let mut config = match &options.cert {
+        Some(cert) => {
+            let (chain, key) = client_identity(cert, options.cert_password.as_deref(), options.key.as_deref())?;
+            builder.with_client_auth_cert(chain, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    // Same as reqwest's own configuration
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+// The built in roots, or only the given CAs when there are any (like curl)
+fn root_store(options: &TlsOptions) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    if options.cacert.is_none() && options.capath.is_none() {
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+        }));
+        return Ok(roots);
+    }
+
+    if let Some(path) = &options.cacert {
+        let certs = read_certs(path)?;
+        if certs.is_empty() {
+            bail!("No certificates in CA file {}", path.display());
+        }
+        for cert in &certs {
+            roots.add(cert).with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+        }
+    }
+
+    if let Some(dir) = &options.capath {
+        let entries = std::fs::read_dir(dir).with_context(|| format!("Can't read CA directory {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            // Anything that isn't a PEM certificate (keys, hash links to them) is skipped
+            if path.is_file() && let Ok(certs) = read_certs(&path) {
+                let der: Vec<_> = certs.into_iter().map(|c| c.0).collect();
+                roots.add_parsable_certificates(&der);
+            }
+        }
+    }
+
+    Ok(roots)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Can't read certificate file {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Invalid PEM in {}", path.display()))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// The certificate chain and key from --cert (and --key)
+fn client_identity(cert: &Path, password: Option<&str>, key: Option<&Path>) -> Result<(Vec<Certificate>, PrivateKey)> {
+    let is_pkcs12 = cert.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("p12") || ext.eq_ignore_ascii_case("pfx"));
+
+    if is_pkcs12 {
+        let data = std::fs::read(cert).with_context(|| format!("Can't read client certificate {}", cert.display()))?;
+        let store = p12_keystore::KeyStore::from_pkcs12(&data, password.unwrap_or(""))
+            .map_err(|e| anyhow::anyhow!("Can't open PKCS#12 file {}: {}", cert.display(), e))?;
+        let (_, chain) = store.private_key_chain()
+            .with_context(|| format!("No private key in PKCS#12 file {}", cert.display()))?;
+        let certs = chain.chain().iter().map(|c| Certificate(c.as_der().to_vec())).collect();
+        return Ok((certs, PrivateKey(chain.key().to_vec())));
+    }
+
+    let certs = read_certs(cert)?;
+    if certs.is_empty() {
+        bail!("No certificates in client certificate file {}", cert.display());
+    }
+    let key_path = key.unwrap_or(cert);
+    let key = read_private_key(key_path)?
+        .with_context(|| format!("No private key in {}", key_path.display()))?;
+    Ok((certs, key))
+}
+
+fn read_private_key(path: &Path) -> Result<Option<PrivateKey>> {
+    let file = File::open(path).with_context(|| format!("Can't read key file {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader).with_context(|| format!("Invalid PEM in {}", path.display()))? {
+            Some(rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key)) => {
+                return Ok(Some(PrivateKey(key)));
+            }
+            Some(_) => {}
+            None => return Ok(None),
+        }
+    }
+}
+
+// Parse --pinnedpubkey: sha256//<base64> hashes separated by `;`, or a PEM
+// or DER public key file
+pub fn parse_pinned_pubkeys(arg: &str) -> Result<Vec<[u8; 32]>> {
+    if arg.starts_with("sha256//") {
+        return arg.split(';')
+            .map(|pin| {
+                let hash = pin.trim().strip_prefix("sha256//")
+                    .with_context(|| format!("Invalid pinned key `{}`: expected sha256//<base64>", pin))?;
+                STANDARD.decode(hash).ok()
+                    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                    .with_context(|| format!("Invalid pinned key `{}`: not a base64 SHA-256 hash", pin))
+            })
+            .collect();
+    }
+
+    let data = std::fs::read(arg).with_context(|| format!("Can't read public key file {}", arg))?;
+    let der = match std::str::from_utf8(&data) {
+        Ok(text) if text.contains("-----BEGIN PUBLIC KEY-----") => {
+            let body: String = text.lines()
+                .skip_while(|line| !line.starts_with("-----BEGIN PUBLIC KEY-----"))
+                .skip(1)
+                .take_while(|line| !line.starts_with("-----END"))
+                .collect();
+            STANDARD.decode(body.trim()).with_context(|| format!("Invalid PEM in {}", arg))?
+        }
+        _ => data,
+    };
+    Ok(vec![Sha256::digest(der).into()])
+}
+
+// Checks the server's certificate as usual (unless --insecure), then that
+// its public key is one of the pinned ones, if any
+struct PinningVerifier {
+    inner: Option<WebPkiVerifier>,
+    pins: Vec<[u8; 32]>,
+    seen: Option<Arc<Mutex<Vec<Certificate>>>>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(seen) = &self.seen {
+            let mut seen = seen.lock().unwrap();
+            seen.clear();
+            seen.push(end_entity.clone());
+            seen.extend(intermediates.iter().cloned());
+        }
+
+        if let Some(inner) = &self.inner {
+            inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        }
+
+        if !self.pins.is_empty() {
+            let spki = subject_public_key_info(&end_entity.0)
+                .ok_or_else(|| rustls::Error::General("can't read the server's public key".to_string()))?;
+            let hash: [u8; 32] = Sha256::digest(spki).into();
+            if !self.pins.contains(&hash) {
+                return Err(rustls::Error::General(format!(
+                    "server public key sha256//{} doesn't match --pinnedpubkey", STANDARD.encode(hash)
+                )));
+            }
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// What a TLS handshake with a server settled on, for verbose output
+#[derive(Debug, Default)]
+pub struct HandshakeInfo {
+    pub version: Option<String>,
+    pub cipher: Option<String>,
+    pub alpn: Option<String>,
+    // The server's certificates, leaf first, as sent even if they failed verification
+    pub chain: Vec<Vec<u8>>,
+    pub error: Option<String>,
+}
+
+// Shake hands with the server at `addr` using the same settings as requests,
+// to report what was negotiated, for --tls-probe. reqwest only exposes the
+// peer certificate of its own connections.
+pub async fn handshake(options: &TlsOptions, addr: SocketAddr, host: &str) -> HandshakeInfo {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut info = HandshakeInfo::default();
+
+    match try_handshake(options, addr, host, seen.clone()).await {
+        Ok(conn) => {
+            info.version = conn.protocol_version().map(|v| format!("{:?}", v));
+            info.cipher = conn.negotiated_cipher_suite().map(|s| format!("{:?}", s.suite()));
+            info.alpn = conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned());
+        }
+        Err(e) => info.error = Some(format!("{:#}", e)),
+    }
+
+    info.chain = seen.lock().unwrap().iter().map(|c| c.0.clone()).collect();
+    info
+}
+
+async fn try_handshake(options: &TlsOptions, addr: SocketAddr, host: &str, seen: Arc<Mutex<Vec<Certificate>>>) -> Result<rustls::ClientConnection> {
+    let config = build_config(options, Some(seen))?;
+    let name = ServerName::try_from(host).with_context(|| format!("Invalid server name {}", host))?;
+
+    let connect = async {
+        let tcp = tokio::net::TcpStream::connect(addr).await?;
+        let tls = tokio_rustls::TlsConnector::from(Arc::new(config)).connect(name, tcp).await?;
+        anyhow::Ok(tls.into_inner().1)
+    };
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, connect).await
+        .context("TLS handshake timed out")?
+}
+
+// One line each for a certificate's subject, issuer and validity
+pub fn describe_certificate(der: &[u8]) -> Vec<String> {
+    match x509_parser::parse_x509_certificate(der) {
+        Ok((_, cert)) => vec![
+            format!("subject: {}", cert.subject()),
+            format!("issuer: {}", cert.issuer()),
+            format!("start date: {}", cert.validity().not_before),
+            format!("expire date: {}", cert.validity().not_after),
+        ],
+        Err(e) => vec![format!("unreadable certificate: {}", e)],
+    }
+}
+
+// The DER SubjectPublicKeyInfo of an X.509 certificate, which is what
+// public key pins hash
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(cert.tbs_certificate.subject_pki.raw)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+    // openssl x509 -in server.pem -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64
+    const SERVER_PIN: &str = "sha256//A4u/zHepe9xx/HsDX0VMCcED6kqp+HXAR929nD67+80=";
+
+    pub(crate) fn fixture(name: &str) -> PathBuf {
+        Path::new(FIXTURES).join(name)
+    }
+
+    pub(crate) fn tls_server(require_client_cert: bool) -> String {
+        tls_server_with(require_client_cert, rustls::ALL_VERSIONS)
+    }
+
+    // An HTTPS server on 127.0.0.1 using the fixture server cert, optionally
+    // requiring a client certificate. Returns its URL.
+    fn tls_server_with(require_client_cert: bool, versions: &[&'static rustls::SupportedProtocolVersion]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://localhost:{}/", listener.local_addr().unwrap().port());
+
+        let certs = read_certs(&fixture("server.pem")).unwrap();
+        let key = read_private_key(&fixture("server.key")).unwrap().unwrap();
+        let builder = rustls::ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .unwrap();
+        let config = if require_client_cert {
+            let mut roots = RootCertStore::empty();
+            roots.add(&read_certs(&fixture("ca.pem")).unwrap()[0]).unwrap();
+            builder.with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed())
+        } else {
+            builder.with_no_client_auth()
+        };
+        let config = Arc::new(config.with_single_cert(certs, key).unwrap());
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut conn = rustls::ServerConnection::new(config.clone()).unwrap();
+                let mut tls = rustls::Stream::new(&mut conn, &mut stream);
+                let mut buf = [0; 4096];
+                // A failed handshake just ends this connection
+                if tls.read(&mut buf).is_ok() {
+                    let _ = tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+                    let _ = tls.flush();
+                }
+            }
+        });
+
+        url
+    }
+
+    async fn get(options: TlsOptions, url: &str) -> Result<String> {
+        let client = reqwest::Client::builder()
+            .use_preconfigured_tls(client_config(&options)?)
+            .build()?;
+        Ok(client.get(url).send().await?.text().await?)
+    }
+
+    #[tokio::test]
+    async fn trusts_cacert() {
+        let url = tls_server(false);
+
+        assert!(get(TlsOptions::default(), &url).await.is_err());
+
+        let options = TlsOptions { cacert: Some(fixture("ca.pem")), ..Default::default() };
+        assert_eq!(get(options, &url).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn trusts_capath() {
+        let url = tls_server(false);
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy(fixture("ca.pem"), dir.path().join("ca.pem")).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a certificate").unwrap();
+
+        let options = TlsOptions { capath: Some(dir.path().to_path_buf()), ..Default::default() };
+        assert_eq!(get(options, &url).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn insecure_skips_verification() {
+        let url = tls_server(false);
+
+        let options = TlsOptions { insecure: true, ..Default::default() };
+        assert_eq!(get(options, &url).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn pinned_pubkey_must_match() {
+        let url = tls_server(false);
+
+        let options = TlsOptions {
+            insecure: true,
+            pinned_pubkeys: parse_pinned_pubkeys(SERVER_PIN).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(get(options, &url).await.unwrap(), "ok");
+
+        let options = TlsOptions {
+            insecure: true,
+            pinned_pubkeys: vec![[0; 32]],
+            ..Default::default()
+        };
+        assert!(get(options, &url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_certificate_pem_and_pkcs12() {
+        let url = tls_server(true);
+        let trusted = TlsOptions { cacert: Some(fixture("ca.pem")), ..Default::default() };
+
+        assert!(get(trusted.clone(), &url).await.is_err());
+
+        let pem = TlsOptions { cert: Some(fixture("client.pem")), key: Some(fixture("client.key")), ..trusted.clone() };
+        assert_eq!(get(pem, &url).await.unwrap(), "ok");
+
+        let pkcs12 = TlsOptions { cert: Some(fixture("client.p12")), cert_password: Some("secret".to_string()), ..trusted };
+        assert_eq!(get(pkcs12, &url).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn handshake_reports_the_connection() {
+        let url = tls_server(false);
+        let port = url::Url::parse(&url).unwrap().port().unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+        let trusted = TlsOptions { cacert: Some(fixture("ca.pem")), ..Default::default() };
+        let info = handshake(&trusted, addr, "localhost").await;
+        assert_eq!(info.error, None);
+        assert_eq!(info.version.as_deref(), Some("TLSv1_3"));
+        assert!(info.cipher.unwrap().starts_with("TLS13_"));
+        assert_eq!(info.chain.len(), 1);
+
+        // The chain is still there to look at when it isn't trusted
+        let info = handshake(&TlsOptions::default(), addr, "localhost").await;
+        assert!(info.error.unwrap().contains("UnknownIssuer"));
+        assert_eq!(info.chain.len(), 1);
+        assert_eq!(info.version, None);
+    }
+
+    #[test]
+    fn describe_server_certificate() {
+        let cert = &read_certs(&fixture("server.pem")).unwrap()[0];
+        let lines = describe_certificate(&cert.0);
+
+        assert_eq!(lines[0], "subject: CN=localhost");
+        assert_eq!(lines[1], "issuer: CN=rusty_curl test CA");
+        assert!(lines[3].starts_with("expire date: "));
+    }
+
+    #[test]
+    fn pkcs12_needs_the_right_password() {
+        let options = TlsOptions { cert: Some(fixture("client.p12")), cert_password: Some("wrong".to_string()), ..Default::default() };
+        assert!(client_config(&options).is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_min_version() {
+        let url = tls_server_with(false, &[&rustls::version::TLS12]);
+        let trusted = TlsOptions { cacert: Some(fixture("ca.pem")), ..Default::default() };
+
+        let tls12 = TlsOptions { min_version: Some(TlsVersion::Tls12), ..trusted.clone() };
+        assert_eq!(get(tls12, &url).await.unwrap(), "ok");
+
+        let tls13 = TlsOptions { min_version: Some(TlsVersion::Tls13), ..trusted };
+        assert!(get(tls13, &url).await.is_err());
+    }
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!(parse_tls_version("1.2"), Ok(TlsVersion::Tls12));
+        assert!(parse_tls_version("1.1").unwrap_err().contains("isn't supported"));
+        assert!(parse_tls_version("2").is_err());
+    }
+
+    #[test]
+    fn parse_pins() {
+        let pins = parse_pinned_pubkeys(&format!("{};sha256//{}", SERVER_PIN, STANDARD.encode([1; 32]))).unwrap();
+        assert_eq!(pins.len(), 2);
+        assert_eq!(pins[1], [1; 32]);
+
+        assert!(parse_pinned_pubkeys("sha256//tooshort").is_err());
+        assert!(parse_pinned_pubkeys("/no/such/key.pem").is_err());
+    }
+
+    #[test]
+    fn spki_matches_openssl() {
+        let cert = &read_certs(&fixture("server.pem")).unwrap()[0];
+        let spki = subject_public_key_info(&cert.0).unwrap();
+
+        let expected = parse_pinned_pubkeys(SERVER_PIN).unwrap()[0];
+        assert_eq!(<[u8; 32]>::from(Sha256::digest(spki)), expected);
+
+        assert_eq!(subject_public_key_info(b"not a certificate"), None);
+    }
+}
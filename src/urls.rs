@@ -0,0 +1,95 @@
+use std::io;
+
+use futures::stream::{self, Stream};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::cli::valid_url;
+
+// The URL on a line of a URL list, or None for blank lines and # comments
+pub fn parse_line(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        None
+    } else {
+        Some(line)
+    }
+}
+
+// Why the URL on line `line_no` of `source` can't be requested, if it can't.
+// This is what a failed result reports for a list that wasn't checked up
+// front, like stdin.
+pub fn check_url(url: &str, source: &str, line_no: usize) -> Option<String> {
+    (!valid_url(url)).then(|| format!("Invalid URL {} at {} line {}", url, source, line_no))
+}
+
+// Read a URL list (or a batch file) one line at a time, yielding each entry with its line number
+// (counting from 1). Lines are read as the stream is polled, so a long list
+// is never held in memory.
+pub fn read_urls<R>(reader: R) -> impl Stream<Item = io::Result<(usize, String)>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    stream::unfold(Some((reader.lines(), 0)), |state| async move {
+        let (mut lines, mut line_no) = state?;
+        loop {
+            line_no += 1;
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if let Some(url) = parse_line(&line) {
+                        let url = url.to_string();
+                        return Some((Ok((line_no, url)), Some((lines, line_no))));
+                    }
+                }
+                Ok(None) => return None,
+                // Stop after reporting the error
+                Err(e) => return Some((Err(e), None)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn parse_line_skips_blanks_and_comments() {
+        assert_eq!(parse_line("  https://example.com  "), Some("https://example.com"));
+        assert_eq!(parse_line(""), None);
+        assert_eq!(parse_line("   "), None);
+        assert_eq!(parse_line("# https://skipped.example.com"), None);
+    }
+
+    #[test]
+    fn check_url_names_the_line() {
+        assert_eq!(check_url("https://example.com", "stdin", 1), None);
+        assert_eq!(
+            check_url("example.com/items", "stdin", 3),
+            Some("Invalid URL example.com/items at stdin line 3".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn read_urls_numbers_lines() {
+        let input: &[u8] = b"https://a.example.com\n\n# comment\n  https://b.example.com\r\n";
+
+        let urls: Vec<_> = read_urls(input).map(|r| r.unwrap()).collect().await;
+
+        assert_eq!(urls, vec![
+            (1, "https://a.example.com".to_string()),
+            (4, "https://b.example.com".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn read_urls_stops_on_error() {
+        let input: &[u8] = b"https://a.example.com\n\xff\xfe\nhttps://b.example.com\n";
+
+        let urls: Vec<_> = read_urls(input).collect().await;
+
+        assert_eq!(urls.len(), 2);
+        assert!(urls[0].is_ok());
+        assert!(urls[1].is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use reqwest::header::{ACCEPT, ACCEPT_ENCODING, CONTENT_LENGTH, HOST, HeaderMap};
+use reqwest::tls::TlsInfo;
+use reqwest::{Request, Response, Version};
+use reqwest_middleware::{Middleware, Next};
+use task_local_extensions::Extensions;
+
+use crate::proxy::ProxyRoutes;
+use crate::tls::{TlsOptions, describe_certificate, handshake};
+
+// Writes a curl style trace of every attempt to stderr: `>` for the request
+// headers (before it's sent, so a request that hangs still shows), then `*`
+// lines about the connection and `<` for the response headers. It sits
+// inside the retries so each attempt shows up.
+pub struct VerboseMiddleware {
+    // Settings for the separate --tls-probe handshake, when asked for
+    probe: Option<TlsOptions>,
+    proxies: ProxyRoutes,
+    // host:port pairs that have been probed already
+    probed: Mutex<HashSet<String>>,
+    out: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl VerboseMiddleware {
+    pub fn new(probe: Option<TlsOptions>, proxies: ProxyRoutes) -> Self {
+        Self::with_writer(probe, proxies, Arc::new(Mutex::new(std::io::stderr())))
+    }
+
+    pub fn with_writer(probe: Option<TlsOptions>, proxies: ProxyRoutes, out: Arc<Mutex<dyn Write + Send>>) -> Self {
+        VerboseMiddleware { probe, proxies, probed: Mutex::new(HashSet::new()), out }
+    }
+
+    // One write at a time so parallel requests don't interleave mid-line
+    fn write(&self, trace: &str) {
+        let _ = self.out.lock().unwrap().write_all(trace.as_bytes());
+    }
+
+    // reqwest only says which certificate the request's own connection got,
+    // not the TLS version or cipher. With --tls-probe those come from a
+    // second handshake, once per host, which is labelled as such: it's a
+    // different connection and may reach a different server.
+    async fn probe_tls(&self, trace: &mut String, host: &str, port: u16, addr: SocketAddr) {
+        let Some(options) = &self.probe else {
+            return;
+        };
+        if !self.probed.lock().unwrap().insert(format!("{}:{}", host, port)) {
+            return;
+        }
+
+        let _ = writeln!(trace, "* TLS probe: a separate connection to {} ({}) port {}", host, addr.ip(), addr.port());
+        let info = handshake(options, addr, host).await;
+        if let (Some(version), Some(cipher)) = (&info.version, &info.cipher) {
+            let _ = writeln!(trace, "*   probe negotiated {} / {}", version, cipher);
+        }
+        match &info.alpn {
+            Some(alpn) => { let _ = writeln!(trace, "*   probe ALPN: server accepted {}", alpn); }
+            None if info.error.is_none() => { let _ = writeln!(trace, "*   probe ALPN: server did not agree on a protocol"); }
+            None => {}
+        }
+        for (i, cert) in info.chain.iter().enumerate() {
+            let _ = writeln!(trace, "*   probe certificate {}:", i);
+            for line in describe_certificate(cert) {
+                let _ = writeln!(trace, "*     {}", line);
+            }
+        }
+        if let Some(error) = &info.error {
+            let _ = writeln!(trace, "*   probe handshake failed: {}", error);
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for VerboseMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let url = req.url().clone();
+        let host = url.host_str().unwrap_or_default().to_string();
+        let port = url.port_or_known_default().unwrap_or_default();
+        // Through a proxy the connection is to the proxy, and the TLS details
+        // aren't available
+        let proxy = self.proxies.proxy_for(&url).cloned();
+        let https = url.scheme() == "https" && proxy.is_none();
+        let (connect_host, connect_port) = match &proxy {
+            Some(proxy) => (
+                proxy.host_str().unwrap_or_default().to_string(),
+                proxy.port_or_known_default().unwrap_or(1080),
+            ),
+            None => (host.clone(), port),
+        };
+        let request_version = req.version();
+
+        let mut sent = String::new();
+        if let Some(proxy) = &proxy {
+            let _ = writeln!(sent, "* Via {} proxy {}:{}", proxy.scheme(), connect_host, connect_port);
+        }
+        let (request_line, request_headers) = request_lines(&req);
+        let _ = writeln!(sent, "{} {}", request_line, version_str(request_version));
+        sent.push_str(&request_headers);
+        self.write(&sent);
+
+        let result = next.run(req, extensions).await;
+
+        let mut trace = String::new();
+        match &result {
+            Ok(resp) => {
+                if let Some(addr) = resp.remote_addr() {
+                    let _ = writeln!(trace, "* Connected to {} ({}) port {}", connect_host, addr.ip(), addr.port());
+                }
+                // The certificate this request's connection was given
+                if let Some(cert) = resp.extensions().get::<TlsInfo>().and_then(|info| info.peer_certificate()) {
+                    let _ = writeln!(trace, "* Server certificate:");
+                    for line in describe_certificate(cert) {
+                        let _ = writeln!(trace, "*   {}", line);
+                    }
+                }
+                if https && let Some(addr) = resp.remote_addr() {
+                    self.probe_tls(&mut trace, &host, port, addr).await;
+                }
+                // What was actually negotiated, e.g. HTTP/2 through ALPN
+                if resp.version() != request_version {
+                    let _ = writeln!(trace, "* Using {}", version_str(resp.version()));
+                }
+                let _ = writeln!(trace, "< {} {}", version_str(resp.version()), resp.status());
+                write_headers(&mut trace, '<', resp.headers());
+                trace.push_str("<\n");
+            }
+            Err(e) => {
+                // Nothing says how far the request got, so look the host up
+                // again to show where it was going
+                match tokio::net::lookup_host((connect_host.as_str(), connect_port)).await {
+                    Ok(addrs) => {
+                        let addrs: Vec<SocketAddr> = addrs.collect();
+                        let ips: Vec<String> = addrs.iter().map(|a| a.ip().to_string()).collect();
+                        let _ = writeln!(trace, "* Host {}:{} resolves to {}", connect_host, connect_port, ips.join(", "));
+                        if https && let Some(addr) = addrs.first() {
+                            self.probe_tls(&mut trace, &host, port, *addr).await;
+                        }
+                    }
+                    Err(e) => { let _ = writeln!(trace, "* Could not resolve host {}: {}", connect_host, e); }
+                }
+                let _ = writeln!(trace, "* Request failed: {}", error_chain(e));
+            }
+        }
+
+        self.write(&trace);
+        result
+    }
+}
+
+// The request line, less the HTTP version, and the headers, including the
+// ones reqwest and hyper add
+fn request_lines(req: &Request) -> (String, String) {
+    let url = req.url();
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+
+    let line = format!("> {} {}", req.method(), target);
+    let mut headers = req.headers().clone();
+    if !headers.contains_key(HOST) && let Some(host) = url.host_str() {
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        if let Ok(value) = host.parse() {
+            headers.insert(HOST, value);
+        }
+    }
+    if !headers.contains_key(ACCEPT) {
+        headers.insert(ACCEPT, "*/*".parse().unwrap());
+    }
+    if !headers.contains_key(ACCEPT_ENCODING) {
+        headers.insert(ACCEPT_ENCODING, "gzip, br".parse().unwrap());
+    }
+    if let Some(len) = req.body().and_then(|b| b.as_bytes()).map(|b| b.len())
+        && !headers.contains_key(CONTENT_LENGTH)
+    {
+        headers.insert(CONTENT_LENGTH, len.into());
+    }
+    let mut lines = String::new();
+    write_headers(&mut lines, '>', &headers);
+    lines.push_str(">\n");
+    (line, lines)
+}
+
+// Credentials are marked sensitive and left out
+fn write_headers(trace: &mut String, prefix: char, headers: &HeaderMap) {
+    for (name, value) in headers {
+        let value = match value.is_sensitive() {
+            true => "<redacted>".into(),
+            false => String::from_utf8_lossy(value.as_bytes()),
+        };
+        let _ = writeln!(trace, "{} {}: {}", prefix, name, value);
+    }
+}
+
+fn version_str(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "HTTP/1.1",
+    }
+}
+
+// The error and everything that caused it, which is where the useful part
+// (refused, timed out, bad certificate) usually is
+fn error_chain(e: &(dyn std::error::Error + 'static)) -> String {
+    let mut chain = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        let message = cause.to_string();
+        if !chain.contains(&message) {
+            chain.push_str(": ");
+            chain.push_str(&message);
+        }
+        source = cause.source();
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+    use reqwest_middleware::ClientBuilder;
+
+    fn traced_client(out: Arc<Mutex<Vec<u8>>>) -> reqwest_middleware::ClientWithMiddleware {
+        ClientBuilder::new(reqwest::Client::new())
+            .with(VerboseMiddleware::with_writer(None, ProxyRoutes::default(), out))
+            .build()
+    }
+
+    fn output(out: &Arc<Mutex<Vec<u8>>>) -> String {
+        String::from_utf8(out.lock().unwrap().clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn traces_request_and_response() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.method(POST).path("/items");
+            then.status(201).header("Location", "/items/1");
+        }).await;
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut secret: reqwest::header::HeaderValue = "Bearer abc".parse().unwrap();
+        secret.set_sensitive(true);
+        traced_client(out.clone())
+            .post(format!("{}/items?x=1", server.base_url()))
+            .header("Authorization", secret)
+            .body("hello")
+            .send().await.unwrap();
+
+        let trace = output(&out);
+        assert!(trace.starts_with("> POST /items?x=1 HTTP/1.1\n"), "{}", trace);
+        assert!(trace.contains(&format!("> host: 127.0.0.1:{}\n", server.port())));
+        assert!(trace.contains("> authorization: <redacted>\n"));
+        assert!(trace.contains("> content-length: 5\n"));
+        assert!(trace.contains(&format!(">\n* Connected to 127.0.0.1 (127.0.0.1) port {}\n< HTTP/1.1 201 Created\n", server.port())), "{}", trace);
+        assert!(trace.contains("< location: /items/1\n"));
+        assert!(trace.ends_with("<\n"));
+    }
+
+    #[tokio::test]
+    async fn traces_the_request_before_the_response_arrives() {
+        let server = MockServer::start_async().await;
+        server.mock_async(|when, then| {
+            when.path("/slow");
+            then.status(200).delay(std::time::Duration::from_secs(30));
+        }).await;
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let client = traced_client(out.clone());
+        let request = client.get(server.url("/slow")).send();
+        // Gives up long before the response would come
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(500), request).await.is_err());
+
+        let trace = output(&out);
+        assert!(trace.starts_with("> GET /slow HTTP/1.1\n"), "{}", trace);
+        assert!(trace.ends_with(">\n"), "{}", trace);
+    }
+
+    #[tokio::test]
+    async fn traces_failures_with_their_cause() {
+        // Nothing listens on port 1
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let result = traced_client(out.clone()).get("http://127.0.0.1:1/").send().await;
+        assert!(result.is_err());
+
+        let trace = output(&out);
+        assert!(trace.starts_with("> GET / HTTP/1.1\n"), "{}", trace);
+        assert!(trace.contains(">\n* Host 127.0.0.1:1 resolves to 127.0.0.1\n"), "{}", trace);
+        assert!(trace.contains("* Request failed: "));
+        assert!(trace.contains("refused"), "{}", trace);
+    }
+
+    #[tokio::test]
+    async fn labels_the_tls_probe_as_a_separate_connection() {
+        let url = crate::tls::tests::tls_server(false);
+        let tls = TlsOptions { cacert: Some(crate::tls::tests::fixture("ca.pem")), ..Default::default() };
+        let base = reqwest::Client::builder()
+            .use_preconfigured_tls(crate::tls::client_config(&tls).unwrap())
+            .tls_info(true)
+            .build()
+            .unwrap();
+
+        // Without the probe, only the certificate of the request's own connection
+        let out = Arc::new(Mutex::new(Vec::new()));
+        ClientBuilder::new(base.clone())
+            .with(VerboseMiddleware::with_writer(None, ProxyRoutes::default(), out.clone()))
+            .build()
+            .get(&url).send().await.unwrap();
+        let trace = output(&out);
+        assert!(trace.contains("* Server certificate:\n*   subject: "), "{}", trace);
+        assert!(!trace.contains("probe"), "{}", trace);
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        ClientBuilder::new(base)
+            .with(VerboseMiddleware::with_writer(Some(tls), ProxyRoutes::default(), out.clone()))
+            .build()
+            .get(&url).send().await.unwrap();
+        let trace = output(&out);
+        assert!(trace.contains("* TLS probe: a separate connection to localhost (127.0.0.1) port "), "{}", trace);
+        assert!(trace.contains("*   probe negotiated TLSv1_3 / "), "{}", trace);
+        assert!(trace.contains("*   probe certificate 0:\n*     subject: "), "{}", trace);
+    }
+}